@@ -0,0 +1,467 @@
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::thread;
+use tiny_http::{Header, Method, Response, Server};
+
+use crate::proto::mime::{self, Part};
+use crate::proto::xclient::Attributes;
+use crate::storage::memory::{MemoryStore, StoredMessage};
+
+static INDEX_HTML: &str = include_str!("http/index.html");
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+#[derive(Debug, Serialize)]
+struct MessageSummary<'a> {
+    id: u64,
+    from: &'a str,
+    recipients: &'a [String],
+    peer_addr: String,
+    size: usize,
+    received_at: String,
+    auth: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    xclient: Option<&'a Attributes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    xforward: Option<&'a Attributes>,
+    subject: Option<String>,
+}
+
+impl<'a> MessageSummary<'a> {
+    fn new(message: &'a StoredMessage, mime: &Part) -> Self {
+        MessageSummary {
+            id: message.id,
+            from: &message.envelope.from,
+            recipients: &message.envelope.recipients,
+            peer_addr: message.envelope.peer_addr.to_string(),
+            size: message.data.len(),
+            received_at: message.envelope.received_at.to_rfc3339(),
+            auth: message.envelope.auth.as_deref(),
+            xclient: message.envelope.xclient.as_ref(),
+            xforward: message.envelope.xforward.as_ref(),
+            subject: mime.subject().map(str::to_string),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct PartSummary {
+    index: usize,
+    content_type: String,
+    filename: Option<String>,
+    attachment: bool,
+    size: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct MessageDetails<'a> {
+    #[serde(flatten)]
+    summary: MessageSummary<'a>,
+    headers: &'a [(String, String)],
+    text_body: Option<&'a str>,
+    html_body: Option<&'a str>,
+    attachments: Vec<String>,
+    parts: Vec<PartSummary>,
+}
+
+impl<'a> MessageDetails<'a> {
+    fn new(message: &'a StoredMessage) -> Self {
+        let mime = &message.mime;
+        let parts = mime
+            .leaves()
+            .iter()
+            .enumerate()
+            .map(|(index, part)| PartSummary {
+                index,
+                content_type: part.content_type.mime_type.clone(),
+                filename: part.filename(),
+                attachment: part.is_attachment(),
+                size: part.body.len(),
+            })
+            .collect();
+
+        MessageDetails {
+            summary: MessageSummary::new(message, mime),
+            headers: &mime.headers,
+            text_body: mime.text_body(),
+            html_body: mime.html_body(),
+            attachments: mime
+                .attachments()
+                .iter()
+                .filter_map(|part| part.filename())
+                .collect(),
+            parts,
+        }
+    }
+}
+
+/// JSON view of the MIME tree of a message.
+#[derive(Debug, Serialize)]
+struct MimeNode<'a> {
+    content_type: &'a str,
+    charset: Option<&'a str>,
+    filename: Option<String>,
+    attachment: bool,
+    headers: &'a [(String, String)],
+    size: usize,
+    text: Option<&'a str>,
+    parts: Vec<MimeNode<'a>>,
+}
+
+impl<'a> From<&'a Part> for MimeNode<'a> {
+    fn from(part: &'a Part) -> Self {
+        MimeNode {
+            content_type: &part.content_type.mime_type,
+            charset: part.content_type.param("charset"),
+            filename: part.filename(),
+            attachment: part.parts.is_empty() && part.is_attachment(),
+            headers: &part.headers,
+            size: part.body.len(),
+            text: part.text.as_deref(),
+            parts: part.parts.iter().map(From::from).collect(),
+        }
+    }
+}
+
+/// Starts the inspection API in a separate thread.
+pub fn serve(addr: SocketAddr, store: Arc<MemoryStore>) -> Result<(), Error> {
+    let server = Server::http(addr).map_err(|err| anyhow!("{}: {}", addr, err))?;
+
+    thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = panic::catch_unwind(AssertUnwindSafe(|| {
+                route(&store, request.method(), request.url())
+            }))
+            .unwrap_or_else(|_| Err(anyhow!("request handler panicked")))
+            .unwrap_or_else(|err| {
+                error!("http: {} {}: {}", request.method(), request.url(), err);
+                empty(500)
+            });
+            if let Err(err) = request.respond(response) {
+                error!("http: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn route(store: &MemoryStore, method: &Method, url: &str) -> Result<HttpResponse, Error> {
+    let path = url.split('?').next().unwrap_or_default();
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match (method, segments.as_slice()) {
+        (Method::Get, []) => Ok(Response::from_data(INDEX_HTML.as_bytes().to_vec())
+            .with_header(header("Content-Type", "text/html; charset=utf-8")?)),
+        (Method::Get, ["api", "messages"]) => {
+            let messages = store.list();
+            let summaries: Vec<MessageSummary> = messages
+                .iter()
+                .map(|message| MessageSummary::new(message, &message.mime))
+                .collect();
+            json(200, &summaries)
+        }
+        (Method::Delete, ["api", "messages"]) => {
+            store.clear();
+            Ok(empty(204))
+        }
+        (Method::Get, ["api", "messages", id]) => match find(store, id) {
+            Some(message) => json(200, &MessageDetails::new(&message)),
+            None => not_found(),
+        },
+        (Method::Get, ["api", "messages", id, "mime"]) => match find(store, id) {
+            Some(message) => json(200, &MimeNode::from(message.mime.as_ref())),
+            None => not_found(),
+        },
+        (Method::Get, ["api", "messages", id, "attachments", name]) => {
+            let message = find(store, id);
+            let name = percent_decode(name);
+            match message
+                .as_ref()
+                .and_then(|message| message.mime.attachment(&name))
+            {
+                Some(part) => part_response(part),
+                None => not_found(),
+            }
+        }
+        (Method::Get, ["api", "messages", id, "parts", index]) => {
+            let message = find(store, id);
+            let part = message.as_ref().and_then(|message| {
+                let mime = &message.mime;
+                let index = index.parse::<usize>().ok()?;
+                mime.leaves().get(index).cloned()
+            });
+            match part {
+                Some(part) => part_response(part),
+                None => not_found(),
+            }
+        }
+        (Method::Get, ["api", "messages", id, "raw"]) => match find(store, id) {
+            Some(message) => Ok(Response::from_data(message.data.to_vec())
+                .with_header(header("Content-Type", "text/plain; charset=utf-8")?)
+                .with_header(header("Content-Security-Policy", "sandbox")?)
+                .with_header(header("X-Content-Type-Options", "nosniff")?)),
+            None => not_found(),
+        },
+        (Method::Delete, ["api", "messages", id]) => match id.parse::<u64>() {
+            Ok(id) if store.delete(id) => Ok(empty(204)),
+            _ => not_found(),
+        },
+        _ => not_found(),
+    }
+}
+
+/// Serves a decoded MIME part. Message content is untrusted, so browsers are
+/// told to render it in a sandbox and not to sniff its type.
+fn part_response(part: &Part) -> Result<HttpResponse, Error> {
+    let mime_type = if is_mime_type(&part.content_type.mime_type) {
+        part.content_type.mime_type.as_str()
+    } else {
+        "application/octet-stream"
+    };
+    let (content_type, body) = match part.text {
+        Some(ref text) => (
+            format!("{}; charset=utf-8", mime_type),
+            text.as_bytes().to_vec(),
+        ),
+        None => (mime_type.to_string(), part.body.clone()),
+    };
+
+    let mut response = Response::from_data(body)
+        .with_header(header("Content-Type", &content_type)?)
+        .with_header(header("Content-Security-Policy", "sandbox")?)
+        .with_header(header("X-Content-Type-Options", "nosniff")?);
+
+    if part.is_attachment() {
+        let filename = part.filename().unwrap_or_else(|| "attachment".to_string());
+        response = response.with_header(header(
+            "Content-Disposition",
+            &content_disposition(&filename),
+        )?);
+    }
+
+    Ok(response)
+}
+
+/// Formats an attachment disposition with an ASCII `filename` for old
+/// clients and the exact name as an RFC 5987 `filename*` parameter.
+fn content_disposition(filename: &str) -> String {
+    let fallback: String = filename
+        .chars()
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+
+    let mut encoded = String::new();
+    for byte in filename.bytes() {
+        match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => encoded.push(byte as char),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+
+    format!(
+        "attachment; filename=\"{}\"; filename*=UTF-8''{}",
+        fallback, encoded
+    )
+}
+
+/// Checks that a MIME type is a `type/subtype` pair of header tokens.
+fn is_mime_type(value: &str) -> bool {
+    let token = |s: &str| {
+        !s.is_empty()
+            && s.bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+    };
+    match value.split_once('/') {
+        Some((kind, subtype)) => token(kind) && token(subtype),
+        None => false,
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    String::from_utf8_lossy(&mime::percent_decode(value)).into_owned()
+}
+
+fn find(store: &MemoryStore, id: &str) -> Option<StoredMessage> {
+    id.parse::<u64>().ok().and_then(|id| store.get(id))
+}
+
+fn header(name: &str, value: &str) -> Result<Header, Error> {
+    Header::from_bytes(name.as_bytes(), value.as_bytes())
+        .map_err(|_| anyhow!("invalid {} header value: {:?}", name, value))
+}
+
+fn json<T: Serialize>(status: u16, value: &T) -> Result<HttpResponse, Error> {
+    let body = serde_json::to_vec(value)?;
+    Ok(Response::from_data(body)
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json")?))
+}
+
+fn empty(status: u16) -> HttpResponse {
+    Response::from_data(Vec::new()).with_status_code(status)
+}
+
+fn not_found() -> Result<HttpResponse, Error> {
+    json(404, &serde_json::json!({ "error": "not found" }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::{Envelope, MessageSink};
+    use std::io::Read;
+
+    fn body(response: HttpResponse) -> String {
+        let mut body = String::new();
+        response.into_reader().read_to_string(&mut body).unwrap();
+        body
+    }
+
+    fn store() -> MemoryStore {
+        let store = MemoryStore::new(10, 1024);
+        let envelope = Envelope::new(
+            "192.0.2.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        store
+            .store(
+                &envelope,
+                b"Subject: test\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\
+\r\n\
+--b\r\n\
+\r\n\
+text\r\n\
+--b\r\n\
+Content-Type: text/csv; name=report.csv\r\n\
+\r\n\
+a,b\r\n\
+--b--\r\n",
+            )
+            .unwrap();
+        store
+    }
+
+    #[test]
+    fn route_test1() {
+        let store = store();
+        let response = route(&store, &Method::Get, "/api/messages").unwrap();
+        assert_eq!(response.status_code().0, 200);
+
+        let list: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(list[0]["id"], 1);
+        assert_eq!(list[0]["from"], "sender@example.com");
+        assert_eq!(list[0]["recipients"][0], "rcpt@example.com");
+        assert_eq!(list[0]["peer_addr"], "192.0.2.1:2525");
+        assert_eq!(list[0]["subject"], "test");
+    }
+
+    #[test]
+    fn route_test2() {
+        let store = store();
+        let response = route(&store, &Method::Get, "/api/messages/1/raw").unwrap();
+        assert_eq!(response.status_code().0, 200);
+        assert!(body(response).ends_with("--b--\r\n"));
+
+        let response = route(&store, &Method::Get, "/api/messages/2/raw").unwrap();
+        assert_eq!(response.status_code().0, 404);
+    }
+
+    #[test]
+    fn route_test3() {
+        let store = store();
+        let response = route(&store, &Method::Delete, "/api/messages/1").unwrap();
+        assert_eq!(response.status_code().0, 204);
+        let response = route(&store, &Method::Delete, "/api/messages/1").unwrap();
+        assert_eq!(response.status_code().0, 404);
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn route_test4() {
+        let store = store();
+        let response = route(&store, &Method::Get, "/api/messages/1").unwrap();
+        let details: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(details["subject"], "test");
+        assert_eq!(details["parts"][1]["filename"], "report.csv");
+        assert_eq!(details["parts"][1]["attachment"], true);
+        assert_eq!(details["text_body"], "text");
+        assert_eq!(details["attachments"][0], "report.csv");
+
+        let response = route(
+            &store,
+            &Method::Get,
+            "/api/messages/1/attachments/report.csv",
+        )
+        .unwrap();
+        assert_eq!(body(response), "a,b");
+
+        let response = route(&store, &Method::Get, "/api/messages/1/mime").unwrap();
+        let mime: serde_json::Value = serde_json::from_str(&body(response)).unwrap();
+        assert_eq!(mime["content_type"], "multipart/mixed");
+        assert_eq!(mime["parts"][1]["content_type"], "text/csv");
+        assert_eq!(mime["parts"][1]["text"], "a,b");
+
+        let response = route(&store, &Method::Get, "/api/messages/1/parts/1").unwrap();
+        assert_eq!(response.status_code().0, 200);
+        assert!(response
+            .headers()
+            .iter()
+            .any(|h| h.field.equiv("Content-Disposition")
+                && h.value.as_str()
+                    == "attachment; filename=\"report.csv\"; filename*=UTF-8''report.csv"));
+        assert_eq!(body(response), "a,b");
+
+        let response = route(&store, &Method::Get, "/api/messages/1/parts/2").unwrap();
+        assert_eq!(response.status_code().0, 404);
+    }
+
+    #[test]
+    fn route_test5() {
+        let store = MemoryStore::new(10, 1024);
+        let envelope = Envelope::new(
+            "192.0.2.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        store
+            .store(
+                &envelope,
+                b"Content-Type: application/\xd1\x84\r\n\
+Content-Disposition: attachment; filename*=utf-8''%D1%84%22.txt\r\n\
+\r\n\
+data\r\n",
+            )
+            .unwrap();
+
+        let response = route(&store, &Method::Get, "/api/messages/1/parts/0").unwrap();
+        assert_eq!(response.status_code().0, 200);
+        let header = |name: &'static str| {
+            response
+                .headers()
+                .iter()
+                .find(|h| h.field.equiv(name))
+                .map(|h| h.value.as_str().to_string())
+        };
+        assert_eq!(
+            header("Content-Type").as_deref(),
+            Some("application/octet-stream")
+        );
+        assert_eq!(
+            header("Content-Disposition").as_deref(),
+            Some("attachment; filename=\"__.txt\"; filename*=UTF-8''%D1%84%22.txt")
+        );
+    }
+}
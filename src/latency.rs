@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Error};
+use rand::prelude::*;
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::thread;
+use std::time::Duration;
+
+/// Stages delay can be added to: the greeting, replies to the commands and
+/// the reply after the message content.
+static STAGES: &[&str] = &[
+    "greeting", "helo", "ehlo", "lhlo", "mail", "rcpt", "data", "bdat", "eod", "rset", "noop",
+    "quit", "vrfy", "expn", "help", "starttls", "auth", "xclient", "xforward",
+];
+
+static MAX_DELAY_MS: f64 = 3_600_000f64;
+
+/// Distribution of the delay in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Distribution {
+    Fixed(f64),
+    Uniform(f64, f64),
+    /// Median and the standard deviation of the logarithm.
+    LogNormal(f64, f64),
+}
+
+impl Distribution {
+    fn parse(spec: &str) -> Result<Self, Error> {
+        let parse = |value: &str| match value.trim().parse::<f64>() {
+            Ok(value) if value.is_finite() && value >= 0f64 => Ok(value),
+            _ => Err(anyhow!("invalid latency value '{}'", value)),
+        };
+
+        if let Some(params) = spec.strip_prefix("lognormal:") {
+            let (median, sigma) = params.split_once(',').ok_or_else(|| {
+                anyhow!("log-normal latency must be given as lognormal:<median>,<sigma>")
+            })?;
+            return Ok(Distribution::LogNormal(parse(median)?, parse(sigma)?));
+        }
+
+        match spec.split_once("..") {
+            Some((min, max)) => {
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err(anyhow!("invalid latency range '{}'", spec));
+                }
+                Ok(Distribution::Uniform(min, max))
+            }
+            None => Ok(Distribution::Fixed(parse(spec)?)),
+        }
+    }
+
+    fn sample(&self) -> Duration {
+        let millis = match *self {
+            Distribution::Fixed(value) => value,
+            Distribution::Uniform(min, max) => min + (max - min) * random::<f64>(),
+            Distribution::LogNormal(median, sigma) => {
+                // Standard normal value with the Box-Muller transform
+                let u1 = 1f64 - random::<f64>();
+                let u2 = random::<f64>();
+                let z = (-2f64 * u1.ln()).sqrt() * (2f64 * PI * u2).cos();
+                median * (sigma * z).exp()
+            }
+        };
+        // Long tail of log-normal distribution with large sigma is capped
+        Duration::from_secs_f64(millis.min(MAX_DELAY_MS) / 1000f64)
+    }
+}
+
+/// Artificial delays before replies emulating slow receiving MTAs.
+#[derive(Debug, Default)]
+pub struct Latency {
+    stages: HashMap<&'static str, Distribution>,
+    /// Delay between the bytes of replies sent one at a time.
+    drip: Option<Distribution>,
+    /// Number of recipients accepted at full speed and the delay added to
+    /// every next RCPT reply.
+    tarpit: Option<(usize, Duration)>,
+}
+
+impl Latency {
+    /// Adds delay given on the command line as `<stage>=<ms>` (fixed),
+    /// `<stage>=<min>..<max>` (uniform) or
+    /// `<stage>=lognormal:<median>,<sigma>` (log-normal). Stage is
+    /// `greeting`, `eod` (end of data) or a command verb.
+    pub fn add(&mut self, spec: &str) -> Result<(), Error> {
+        let (stage, distribution) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("latency must be given as <stage>=<ms>"))?;
+        let stage = stage.trim().to_ascii_lowercase();
+        let stage = STAGES
+            .iter()
+            .find(|&&name| name == stage)
+            .ok_or_else(|| anyhow!("unknown latency stage '{}'", stage))?;
+
+        self.stages
+            .insert(stage, Distribution::parse(distribution.trim())?);
+        Ok(())
+    }
+
+    /// Returns the delay before the reply at the stage, if any.
+    pub fn delay(&self, stage: &str) -> Option<Duration> {
+        self.stages
+            .get(stage.to_ascii_lowercase().as_str())
+            .map(Distribution::sample)
+    }
+
+    pub fn sleep(&self, stage: &str) {
+        if let Some(delay) = self.delay(stage) {
+            thread::sleep(delay);
+        }
+    }
+
+    /// Makes replies to be sent one byte at a time with the delay between
+    /// bytes given the same way as the stage delay.
+    pub fn set_drip(&mut self, spec: &str) -> Result<(), Error> {
+        self.drip = Some(Distribution::parse(spec.trim())?);
+        Ok(())
+    }
+
+    pub fn is_drip(&self) -> bool {
+        self.drip.is_some()
+    }
+
+    /// Returns the delay before the next byte of the reply if replies are
+    /// sent one byte at a time.
+    pub fn drip(&self) -> Option<Duration> {
+        self.drip.as_ref().map(Distribution::sample)
+    }
+
+    /// Slows down RCPT replies after `after` recipients in the session, the
+    /// delay grows by `step` with every recipient.
+    pub fn set_tarpit(&mut self, after: usize, step: Duration) {
+        self.tarpit = Some((after, step));
+    }
+
+    /// Returns the tarpit delay before the reply to the RCPT command with
+    /// the given number in the session, counting from 1.
+    pub fn tarpit(&self, recipient: usize) -> Option<Duration> {
+        match self.tarpit {
+            Some((after, step)) if recipient > after => {
+                let millis = step.as_millis() as f64 * (recipient - after) as f64;
+                Some(Duration::from_secs_f64(millis.min(MAX_DELAY_MS) / 1000f64))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn add_test1() {
+        let mut latency = Latency::default();
+        latency.add("RCPT=300").unwrap();
+        latency.add("eod=1000..2000").unwrap();
+        latency.add("greeting=lognormal:500,0.5").unwrap();
+
+        assert_eq!(latency.delay("rcpt"), Some(Duration::from_millis(300)));
+        assert_eq!(latency.delay("MAIL"), None);
+        for _ in 0..100 {
+            let delay = latency.delay("eod").unwrap();
+            assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_secs(2));
+            assert!(latency.delay("greeting").is_some());
+        }
+
+        for spec in [
+            "rcpt",
+            "mali=100",
+            "rcpt=-1",
+            "rcpt=fast",
+            "rcpt=500..100",
+            "eod=lognormal:500",
+        ] {
+            assert!(latency.add(spec).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn tarpit_test1() {
+        let mut latency = Latency::default();
+        assert_eq!(latency.tarpit(100), None);
+        assert!(!latency.is_drip());
+
+        latency.set_tarpit(3, Duration::from_millis(500));
+        assert_eq!(latency.tarpit(1), None);
+        assert_eq!(latency.tarpit(3), None);
+        assert_eq!(latency.tarpit(4), Some(Duration::from_millis(500)));
+        assert_eq!(latency.tarpit(7), Some(Duration::from_secs(2)));
+        assert_eq!(
+            latency.tarpit(usize::MAX),
+            Some(Duration::from_secs_f64(MAX_DELAY_MS / 1000f64))
+        );
+
+        latency.set_drip("10..20").unwrap();
+        assert!(latency.is_drip());
+        let delay = latency.drip().unwrap();
+        assert!(delay >= Duration::from_millis(10) && delay <= Duration::from_millis(20));
+        assert!(latency.set_drip("slow").is_err());
+    }
+}
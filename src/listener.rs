@@ -0,0 +1,68 @@
+use anyhow::{anyhow, Error};
+use std::net::SocketAddr;
+
+/// Listen address together with per-listener options given on the command
+/// line as `<addr>[,<option>...]`, e.g. `0.0.0.0:465,tls`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Listener {
+    pub addr: SocketAddr,
+    /// TLS handshake is performed right after accepting the connection.
+    pub implicit_tls: bool,
+    /// Connections come through a proxy which sends PROXY protocol header
+    /// with the original client address first.
+    pub proxy: bool,
+}
+
+impl Listener {
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        let mut items = spec.split(',');
+        let addr = items
+            .next()
+            .unwrap_or_default()
+            .trim()
+            .parse::<SocketAddr>()?;
+
+        let mut listener = Listener {
+            addr,
+            implicit_tls: false,
+            proxy: false,
+        };
+
+        for option in items {
+            match option.trim() {
+                "tls" => listener.implicit_tls = true,
+                "proxy" => listener.proxy = true,
+                option => return Err(anyhow!("{}: unknown listener option '{}'", spec, option)),
+            }
+        }
+
+        Ok(listener)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test1() {
+        let listener = Listener::parse("127.0.0.1:2500").unwrap();
+        assert_eq!(listener.addr, "127.0.0.1:2500".parse().unwrap());
+        assert!(!listener.implicit_tls);
+
+        let listener = Listener::parse("[::1]:465,tls").unwrap();
+        assert_eq!(listener.addr, "[::1]:465".parse().unwrap());
+        assert!(listener.implicit_tls);
+        assert!(!listener.proxy);
+
+        let listener = Listener::parse("0.0.0.0:25,proxy,tls").unwrap();
+        assert!(listener.implicit_tls);
+        assert!(listener.proxy);
+    }
+
+    #[test]
+    fn parse_test2() {
+        assert!(Listener::parse("127.0.0.1").is_err());
+        assert!(Listener::parse("127.0.0.1:465,ssl").is_err());
+    }
+}
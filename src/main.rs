@@ -7,13 +7,11 @@ extern crate lazy_static;
 extern crate ctrlc;
 extern crate net2;
 extern crate rand;
-extern crate regex;
 extern crate threadpool;
 
 use net2::TcpStreamExt;
 use std::io::prelude::*;
 use std::io::BufReader;
-use std::io::BufWriter;
 use std::io::Write;
 use std::net::TcpStream;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
@@ -23,14 +21,30 @@ use std::time;
 
 use anyhow::{anyhow, Error};
 use clap::{crate_authors, crate_version, App, Arg, ArgMatches};
+use ipnet::IpNet;
 use net2::TcpBuilder;
 use threadpool::ThreadPool;
 
+mod http;
+mod latency;
+mod listener;
 mod proto;
+mod proxy;
+mod storage;
+mod tls;
 
+use crate::latency::Latency;
+use crate::listener::Listener;
+use crate::proto::auth::{self, Authenticator, TokenValidator};
+use crate::proto::directory::Directory;
+use crate::proto::greylist::Greylist;
+use crate::proto::policy::{Fault, Policy};
 use crate::proto::reply::*;
 use crate::proto::state::*;
 use crate::proto::*;
+use crate::storage::memory::MemoryStore;
+use crate::storage::MessageSink;
+use crate::tls::Stream;
 
 static READ_TIMEOUT_MS: u32 = 1000 * 30;
 static LISTEN_BACKLOG: i32 = 256;
@@ -50,18 +64,101 @@ impl Stat {
     }
 }
 
+struct Config {
+    policy: Option<Arc<Policy>>,
+    greylist: Option<Arc<Greylist>>,
+    sinks: Vec<Arc<dyn MessageSink>>,
+    tls: Option<Arc<rustls::ServerConfig>>,
+    starttls: bool,
+    authenticator: Option<Arc<Authenticator>>,
+    token_validator: Option<Arc<dyn TokenValidator>>,
+    dsn_fail_ratio: Option<f32>,
+    directory: Option<Arc<Directory>>,
+    vrfy_disabled: bool,
+    lmtp: bool,
+    lmtp_fail_ratio: f32,
+    /// Networks allowed to use XCLIENT and XFORWARD commands.
+    xclient_peers: Vec<IpNet>,
+    latency: Latency,
+    /// How long the connection is kept without reading after the stall
+    /// fault. The worker serving the connection is busy all this time.
+    stall_timeout: time::Duration,
+}
+
 fn write_reply<W>(writer: &mut W, reply: &Reply) -> Result<(), Error>
 where
     W: Write,
 {
     writer.write_all(format!("{}", reply).as_bytes())?;
-    writer.flush()?;
 
     Ok(())
 }
 
-fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
-    let peer_addr = match stream.peer_addr() {
+/// Sends all replies accumulated so far to the client at once, or one byte
+/// at a time in the drip mode.
+fn flush_replies<W>(writer: &mut W, replies: &mut Vec<u8>, latency: &Latency) -> Result<(), Error>
+where
+    W: Write,
+{
+    if latency.is_drip() {
+        for (i, byte) in replies.iter().enumerate() {
+            if i > 0 {
+                thread::sleep(latency.drip().unwrap_or_default());
+            }
+            writer.write_all(&[*byte])?;
+            writer.flush()?;
+        }
+    } else {
+        writer.write_all(replies)?;
+        writer.flush()?;
+    }
+    replies.clear();
+
+    Ok(())
+}
+
+/// Breaks the connection with the fault instead of sending the reply which
+/// starts at `reply_start` in the batch of replies.
+fn inject_fault(
+    stream: &mut Stream,
+    replies: &mut Vec<u8>,
+    reply_start: usize,
+    fault: Fault,
+    config: &Config,
+) -> Result<(), Error> {
+    match fault {
+        Fault::Close => replies.truncate(reply_start),
+        Fault::Reset => {
+            replies.truncate(reply_start);
+            // Closing the socket with zero linger time sends RST
+            TcpStreamExt::set_linger(stream.tcp(), Some(time::Duration::ZERO))?;
+        }
+        Fault::Stall => {}
+        Fault::Partial => replies.truncate(reply_start + (replies.len() - reply_start) / 2),
+        Fault::Shutdown => {
+            replies.truncate(reply_start);
+            write_reply(replies, &Reply::service_unavailable())?;
+        }
+    }
+
+    flush_replies(stream, replies, &config.latency)?;
+
+    // Nothing is read anymore, so the client blocks once the socket buffers
+    // are full.
+    if fault == Fault::Stall {
+        thread::sleep(config.stall_timeout);
+    }
+
+    Ok(())
+}
+
+fn handle_connection(
+    mut stream: TcpStream,
+    listener: Listener,
+    config: Arc<Config>,
+    stat: Arc<Stat>,
+) {
+    let mut peer_addr = match stream.peer_addr() {
         Ok(peer_addr) => peer_addr,
         Err(err) => {
             error!("{}", err);
@@ -74,22 +171,101 @@ fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
         return;
     }
 
-    let mut buffer = String::with_capacity(IO_BUFFER_CAPACITY);
-    let mut reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
+    // The proxy sends the header before anything else, including the TLS
+    // handshake on implicit TLS listeners.
+    if listener.proxy {
+        match proxy::read_header(&mut stream) {
+            Ok(Some(client_addr)) => {
+                debug!("{}: proxied connection from {}", peer_addr, client_addr);
+                peer_addr = client_addr;
+            }
+            Ok(None) => {}
+            Err(err) => {
+                error!("{}: {}", peer_addr, err);
+                return;
+            }
+        }
+    }
+
+    let mut stream = Stream::Plain(stream);
     let mut smtp = Protocol::new();
 
-    smtp.set_reject_ratio(reject_ratio);
+    if listener.implicit_tls {
+        let tls = config.tls.clone().unwrap();
+        stream = match stream.upgrade(tls) {
+            Ok(stream) => stream,
+            Err(err) => {
+                error!("{}: TLS handshake failed: {}", peer_addr, err);
+                return;
+            }
+        };
+        smtp.tls_started();
+    }
+
+    let mut buffer = String::with_capacity(IO_BUFFER_CAPACITY);
+    let mut reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, stream);
+
+    smtp.set_lmtp(config.lmtp);
+    smtp.set_lmtp_fail_ratio(config.lmtp_fail_ratio);
+    if let Some(ref policy) = config.policy {
+        smtp.set_policy(policy.clone());
+    }
+    if let Some(ref greylist) = config.greylist {
+        smtp.set_greylist(greylist.clone());
+    }
+    smtp.set_peer_addr(peer_addr);
+    smtp.set_tls_available(config.starttls);
+    if let Some(ref authenticator) = config.authenticator {
+        smtp.set_authenticator(authenticator.clone());
+    }
+    if let Some(ref validator) = config.token_validator {
+        smtp.set_token_validator(validator.clone());
+    }
+    if let Some(ref directory) = config.directory {
+        smtp.set_directory(directory.clone());
+    }
+    smtp.set_vrfy_disabled(config.vrfy_disabled);
+    smtp.set_xclient_allowed(
+        config
+            .xclient_peers
+            .iter()
+            .any(|net| net.contains(&peer_addr.ip())),
+    );
+    if let Some(ratio) = config.dsn_fail_ratio {
+        smtp.set_dsn_fail_ratio(ratio);
+    }
+    for sink in &config.sinks {
+        smtp.add_sink(sink.clone());
+    }
+
+    // Replies to pipelined commands (RFC 2920) are collected here and sent
+    // in one write once the client's whole batch has been processed.
+    let mut replies = Vec::with_capacity(IO_BUFFER_CAPACITY);
+    // RCPT commands in the whole session, tarpit doesn't start over with
+    // the next transaction.
+    let mut recipients = 0;
 
     {
         let reply = smtp.start();
-
-        if let Err(err) = write_reply(&mut writer, &reply) {
+        config.latency.sleep("greeting");
+        if let Err(err) = write_reply(&mut replies, &reply) {
             error!("{}: {}", peer_addr, err);
             return;
         }
     }
 
+    if let Some(fault) = smtp.take_fault() {
+        info!("{}: injecting {:?} fault", peer_addr, fault);
+        if let Err(err) = inject_fault(reader.get_mut(), &mut replies, 0, fault, &config) {
+            error!("{}: {}", peer_addr, err);
+        }
+        return;
+    }
+    if let Err(err) = flush_replies(reader.get_mut(), &mut replies, &config.latency) {
+        error!("{}: {}", peer_addr, err);
+        return;
+    }
+
     loop {
         if let Ok(bytes_read) = reader.read_line(&mut buffer) {
             if bytes_read == 0 {
@@ -97,8 +273,14 @@ fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
             }
 
             let status: u16;
+            let reply_start = replies.len();
 
             {
+                let verb = buffer
+                    .split_whitespace()
+                    .next()
+                    .unwrap_or_default()
+                    .to_ascii_lowercase();
                 let reply = match smtp.process_command(buffer.as_str()) {
                     Ok(reply) => reply,
                     Err(err) => {
@@ -107,7 +289,18 @@ fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
                     }
                 };
 
-                if let Err(err) = write_reply(&mut writer, &reply) {
+                // The reply to BDAT is delayed once the chunk has been read
+                if verb != "bdat" {
+                    config.latency.sleep(&verb);
+                }
+                if verb == "rcpt" {
+                    recipients += 1;
+                    if let Some(delay) = config.latency.tarpit(recipients) {
+                        thread::sleep(delay);
+                    }
+                }
+
+                if let Err(err) = write_reply(&mut replies, &reply) {
                     error!("{}: {}", peer_addr, err);
                     break;
                 }
@@ -119,16 +312,64 @@ fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
                 stat.rejected.fetch_add(1, Ordering::SeqCst);
             }
 
-            if smtp.is_data() {
-                match smtp.process_data(&mut reader) {
-                    Ok(reply) => {
-                        if let Err(err) = write_reply(&mut writer, &reply) {
+            if let Some(fault) = smtp.take_fault() {
+                info!("{}: injecting {:?} fault", peer_addr, fault);
+                if let Err(err) =
+                    inject_fault(reader.get_mut(), &mut replies, reply_start, fault, &config)
+                {
+                    error!("{}: {}", peer_addr, err);
+                }
+                break;
+            }
+
+            // The client waits for the reply to STARTTLS and DATA before
+            // sending anything else, so they always end the batch.
+            if reader.buffer().is_empty() || smtp.is_starttls() || smtp.is_data() {
+                if let Err(err) = flush_replies(reader.get_mut(), &mut replies, &config.latency) {
+                    error!("{}: {}", peer_addr, err);
+                    break;
+                }
+            }
+
+            if smtp.is_starttls() {
+                // Anything the client has sent after STARTTLS but before the
+                // handshake is dropped together with the reader's buffer.
+                let tls = config.tls.clone().unwrap();
+                match reader.into_inner().upgrade(tls) {
+                    Ok(stream) => reader = BufReader::with_capacity(IO_BUFFER_CAPACITY, stream),
+                    Err(err) => {
+                        error!("{}: TLS handshake failed: {}", peer_addr, err);
+                        break;
+                    }
+                }
+                smtp.tls_started();
+            }
+
+            if smtp.is_data() || smtp.is_chunk() {
+                // Message is complete after DATA or the last BDAT chunk.
+                let complete = smtp.is_data() || smtp.is_last_chunk();
+                let result = if smtp.is_data() {
+                    smtp.process_data(&mut reader)
+                } else {
+                    smtp.process_chunk(&mut reader)
+                };
+                let reply_start = replies.len();
+
+                match result {
+                    Ok(message_replies) => {
+                        config.latency.sleep(if complete { "eod" } else { "bdat" });
+                        let result = message_replies
+                            .iter()
+                            .try_for_each(|reply| write_reply(&mut replies, reply));
+                        if let Err(err) = result {
                             error!("{}: {}", peer_addr, err);
                             break;
                         }
-                        if reply.status >= 400 {
+                        // In LMTP mode the message is accepted once it is
+                        // delivered to any of the recipients.
+                        if !message_replies.iter().any(|reply| reply.status < 400) {
                             stat.rejected.fetch_add(1, Ordering::SeqCst);
-                        } else {
+                        } else if complete {
                             stat.accepted.fetch_add(1, Ordering::SeqCst);
                         }
                     }
@@ -137,9 +378,30 @@ fn handle_connection(stream: TcpStream, reject_ratio: f32, stat: Arc<Stat>) {
                         break;
                     }
                 };
+
+                if let Some(fault) = smtp.take_fault() {
+                    info!("{}: injecting {:?} fault", peer_addr, fault);
+                    if let Err(err) =
+                        inject_fault(reader.get_mut(), &mut replies, reply_start, fault, &config)
+                    {
+                        error!("{}: {}", peer_addr, err);
+                    }
+                    break;
+                }
+
+                if reader.buffer().is_empty() {
+                    if let Err(err) = flush_replies(reader.get_mut(), &mut replies, &config.latency)
+                    {
+                        error!("{}: {}", peer_addr, err);
+                        break;
+                    }
+                }
             }
 
             if smtp.is_done() {
+                if let Err(err) = flush_replies(reader.get_mut(), &mut replies, &config.latency) {
+                    error!("{}: {}", peer_addr, err);
+                }
                 break;
             }
 
@@ -157,28 +419,167 @@ fn run(matches: &ArgMatches) -> Result<(), Error> {
         return Err(anyhow!("number of workers can't be zero"));
     }
 
-    let addr = matches
-        .value_of("address")
+    let listeners = matches
+        .values_of("address")
         .unwrap()
-        .parse::<std::net::SocketAddr>()?;
+        .map(Listener::parse)
+        .collect::<Result<Vec<_>, _>>()?;
 
     let reject_ratio = matches.value_of("ratio").unwrap().parse::<f32>()?;
     if !(0f32..=1f32).contains(&reject_ratio) {
         return Err(anyhow!("reject ratio coefficient must be between 0 and 1"));
     }
 
-    let stat = Arc::new(Stat::new());
+    // Reject ratio is a shorthand for the rule rejecting any recipient
+    let mut policy = match matches.value_of("policy") {
+        Some(path) => Policy::load(path)?,
+        None => Policy::default(),
+    };
+    if let Some(values) = matches.values_of("fault") {
+        for value in values {
+            policy.add_fault(value)?;
+        }
+    }
+    if reject_ratio > 0f32 {
+        policy.add_reject_ratio(reject_ratio);
+    }
+    let policy = if policy.is_empty() {
+        None
+    } else {
+        Some(Arc::new(policy))
+    };
 
-    let tcp = if addr.is_ipv4() {
-        TcpBuilder::new_v4()?
+    let dsn_fail_ratio = match matches.value_of("dsn_fail_ratio") {
+        Some(ratio) => Some(ratio.parse::<f32>()?),
+        None if matches.is_present("dsn") => Some(0f32),
+        None => None,
+    };
+    if let Some(ratio) = dsn_fail_ratio {
+        if !(0f32..=1f32).contains(&ratio) {
+            return Err(anyhow!("DSN failure ratio must be between 0 and 1"));
+        }
+    }
+
+    let mut sinks = Vec::new();
+
+    match (matches.value_of("storage"), matches.value_of("maildir")) {
+        (Some(spec), _) => sinks.push(storage::open_sink(spec)?),
+        (None, Some(path)) => sinks.push(storage::open_sink(&format!("maildir:{}", path))?),
+        (None, None) => {}
+    };
+
+    if let Some(http_addr) = matches.value_of("http") {
+        let http_addr = http_addr.parse::<std::net::SocketAddr>()?;
+        let max_messages = matches
+            .value_of("store_messages")
+            .unwrap()
+            .parse::<usize>()?;
+        let max_bytes = matches.value_of("store_bytes").unwrap().parse::<usize>()?;
+        let store = Arc::new(MemoryStore::new(max_messages, max_bytes));
+        http::serve(http_addr, store.clone())?;
+        sinks.push(store);
+    }
+
+    let starttls = matches.is_present("starttls");
+    let tls = if starttls || listeners.iter().any(|listener| listener.implicit_tls) {
+        Some(tls::load_config(
+            matches.value_of("tls_cert"),
+            matches.value_of("tls_key"),
+        )?)
     } else {
-        TcpBuilder::new_v6()?
+        None
     };
 
-    let listener = tcp
-        .reuse_address(true)?
-        .bind(addr)?
-        .listen(LISTEN_BACKLOG)?;
+    let authenticator = match matches.value_of("auth") {
+        Some(spec) => Some(Arc::new(Authenticator::parse(spec)?)),
+        None => None,
+    };
+
+    let token_validator = match matches.value_of("oauth") {
+        Some(spec) => Some(auth::parse_token_validator(spec)?),
+        None => None,
+    };
+
+    let lmtp_fail_ratio = match matches.value_of("lmtp_fail_ratio") {
+        Some(ratio) => ratio.parse::<f32>()?,
+        None => 0f32,
+    };
+    if !(0f32..=1f32).contains(&lmtp_fail_ratio) {
+        return Err(anyhow!("LMTP failure ratio must be between 0 and 1"));
+    }
+
+    let directory = match matches.value_of("directory") {
+        Some(path) => Some(Arc::new(Directory::load(path)?)),
+        None => None,
+    };
+
+    let xclient_peers = match matches.values_of("xclient_peer") {
+        Some(values) => values
+            .map(|value| {
+                value
+                    .parse::<IpNet>()
+                    .or_else(|_| value.parse::<std::net::IpAddr>().map(IpNet::from))
+                    .map_err(|_| anyhow!("invalid XCLIENT peer '{}'", value))
+            })
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+
+    let greylist = match matches.value_of("greylist") {
+        Some(delay) => {
+            let delay = delay.parse::<u64>()?;
+            let window = matches
+                .value_of("greylist_window")
+                .unwrap()
+                .parse::<u64>()?;
+            let expire = matches
+                .value_of("greylist_expire")
+                .unwrap()
+                .parse::<u64>()?;
+            Some(Arc::new(Greylist::new(
+                time::Duration::from_secs(delay),
+                time::Duration::from_secs(window),
+                time::Duration::from_secs(expire),
+            )))
+        }
+        None => None,
+    };
+
+    let mut latency = Latency::default();
+    if let Some(values) = matches.values_of("latency") {
+        for value in values {
+            latency.add(value)?;
+        }
+    }
+    if let Some(drip) = matches.value_of("drip") {
+        latency.set_drip(drip)?;
+    }
+    if let Some(after) = matches.value_of("tarpit") {
+        let step = matches.value_of("tarpit_step").unwrap().parse::<u64>()?;
+        latency.set_tarpit(after.parse::<usize>()?, time::Duration::from_millis(step));
+    }
+
+    let config = Arc::new(Config {
+        policy,
+        greylist,
+        sinks,
+        tls,
+        starttls,
+        authenticator,
+        token_validator,
+        dsn_fail_ratio,
+        directory,
+        vrfy_disabled: matches.is_present("vrfy_disabled"),
+        lmtp: matches.is_present("lmtp"),
+        lmtp_fail_ratio,
+        xclient_peers,
+        latency,
+        stall_timeout: time::Duration::from_secs(
+            matches.value_of("stall_timeout").unwrap().parse::<u64>()?,
+        ),
+    });
+
+    let stat = Arc::new(Stat::new());
 
     let pool = ThreadPool::new(workers);
 
@@ -190,19 +591,36 @@ fn run(matches: &ArgMatches) -> Result<(), Error> {
         r.store(false, Ordering::SeqCst);
     })?;
 
-    // Accept and process connections in separate thread
-    let s = stat.clone();
-    thread::spawn(move || loop {
-        let (stream, _addr) = match listener.accept() {
-            Ok(result) => result,
-            Err(err) => {
-                error!("accept failed: {:?}", err);
-                break;
-            }
+    // Accept and process connections in separate thread per listen address
+    for listener in listeners {
+        let tcp = if listener.addr.is_ipv4() {
+            TcpBuilder::new_v4()?
+        } else {
+            TcpBuilder::new_v6()?
         };
-        let s = s.clone();
-        pool.execute(move || handle_connection(stream, reject_ratio, s));
-    });
+
+        let socket = tcp
+            .reuse_address(true)?
+            .bind(listener.addr)?
+            .listen(LISTEN_BACKLOG)?;
+
+        let s = stat.clone();
+        let c = config.clone();
+        let pool = pool.clone();
+        thread::spawn(move || loop {
+            let (stream, _addr) = match socket.accept() {
+                Ok(result) => result,
+                Err(err) => {
+                    error!("accept failed: {:?}", err);
+                    break;
+                }
+            };
+            let s = s.clone();
+            let c = c.clone();
+            let listener = listener.clone();
+            pool.execute(move || handle_connection(stream, listener, c, s));
+        });
+    }
 
     // Monitor if Ctrl-C was pressed
     let sleep_interval = time::Duration::from_millis(10);
@@ -237,7 +655,9 @@ fn main() {
                 .default_value("127.0.0.1:2500")
                 .value_name("addr")
                 .required(false)
-                .help("Address to listen"),
+                .multiple(true)
+                .number_of_values(1)
+                .help("Address to listen. Append ',tls' for implicit TLS (SMTPS), ',proxy' to expect PROXY protocol header. Can be repeated"),
         )
         .arg(
             Arg::with_name("workers")
@@ -259,6 +679,256 @@ fn main() {
                 .required(false)
                 .help("Ratio of emails to reject. Must be between 0 and 1"),
         )
+        .arg(
+            Arg::with_name("fault")
+                .long("fault")
+                .takes_value(true)
+                .value_name("stage=fault")
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .help(
+                    "Break the connection at the stage with probability: \
+                     <stage>=<fault>[:<probability>]. Fault is one of 'close', 'reset', \
+                     'stall', 'partial' or 'shutdown'. Can be repeated",
+                ),
+        )
+        .arg(
+            Arg::with_name("stall_timeout")
+                .long("stall-timeout")
+                .takes_value(true)
+                .value_name("secs")
+                .default_value("300")
+                .required(false)
+                .help(
+                    "Time a connection is kept open without reading after the stall fault. \
+                     The connection occupies a worker meanwhile",
+                ),
+        )
+        .arg(
+            Arg::with_name("greylist")
+                .long("greylist")
+                .takes_value(true)
+                .value_name("secs")
+                .required(false)
+                .help(
+                    "Reject the first attempt for every client address, sender and recipient \
+                     with 451 and accept retries after the given delay",
+                ),
+        )
+        .arg(
+            Arg::with_name("greylist_window")
+                .long("greylist-window")
+                .takes_value(true)
+                .value_name("secs")
+                .default_value("14400")
+                .required(false)
+                .help("Time after the greylisting delay retries are accepted within"),
+        )
+        .arg(
+            Arg::with_name("greylist_expire")
+                .long("greylist-expire")
+                .takes_value(true)
+                .value_name("secs")
+                .default_value("3110400")
+                .required(false)
+                .help("Time a retried triplet is accepted for since it was last seen"),
+        )
+        .arg(
+            Arg::with_name("latency")
+                .long("latency")
+                .takes_value(true)
+                .value_name("stage=ms")
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .help(
+                    "Delay before the reply at the stage: 'greeting', 'eod' or a command verb. \
+                     Delay is fixed (rcpt=300), uniform (rcpt=100..500) or log-normal \
+                     (eod=lognormal:<median>,<sigma>) in milliseconds. Can be repeated",
+                ),
+        )
+        .arg(
+            Arg::with_name("drip")
+                .long("drip")
+                .takes_value(true)
+                .value_name("ms")
+                .required(false)
+                .help(
+                    "Send replies one byte at a time with the delay between bytes given the \
+                     same way as --latency",
+                ),
+        )
+        .arg(
+            Arg::with_name("tarpit")
+                .long("tarpit")
+                .takes_value(true)
+                .value_name("count")
+                .required(false)
+                .help("Slow down RCPT replies after the given number of recipients in the session"),
+        )
+        .arg(
+            Arg::with_name("tarpit_step")
+                .long("tarpit-step")
+                .takes_value(true)
+                .value_name("ms")
+                .default_value("1000")
+                .required(false)
+                .help("Delay added to every next RCPT reply in the tarpit"),
+        )
+        .arg(
+            Arg::with_name("policy")
+                .long("policy")
+                .takes_value(true)
+                .value_name("path")
+                .required(false)
+                .help("File with rules deciding which commands to reject and how"),
+        )
+        .arg(
+            Arg::with_name("directory")
+                .long("directory")
+                .takes_value(true)
+                .value_name("path")
+                .required(false)
+                .help("File with users and aliases to answer VRFY and EXPN commands"),
+        )
+        .arg(
+            Arg::with_name("vrfy_disabled")
+                .long("no-vrfy")
+                .required(false)
+                .help("Reply 252 to VRFY and disable EXPN even if directory is given"),
+        )
+        .arg(
+            Arg::with_name("dsn")
+                .long("dsn")
+                .required(false)
+                .help("Generate delivery status notifications for rejected recipients"),
+        )
+        .arg(
+            Arg::with_name("dsn_fail_ratio")
+                .long("dsn-fail-ratio")
+                .takes_value(true)
+                .value_name("num")
+                .required(false)
+                .help(
+                    "Ratio of accepted recipients to fail after the message is accepted. \
+                     Implies --dsn",
+                ),
+        )
+        .arg(
+            Arg::with_name("lmtp")
+                .long("lmtp")
+                .required(false)
+                .help("Speak LMTP instead of SMTP"),
+        )
+        .arg(
+            Arg::with_name("lmtp_fail_ratio")
+                .long("lmtp-fail-ratio")
+                .takes_value(true)
+                .value_name("num")
+                .required(false)
+                .requires("lmtp")
+                .help("Ratio of recipients to fail delivery to after DATA in LMTP mode"),
+        )
+        .arg(
+            Arg::with_name("xclient_peer")
+                .long("xclient-peer")
+                .takes_value(true)
+                .value_name("net")
+                .multiple(true)
+                .number_of_values(1)
+                .required(false)
+                .help(
+                    "Address or network allowed to use XCLIENT and XFORWARD commands. \
+                     Can be repeated",
+                ),
+        )
+        .arg(
+            Arg::with_name("starttls")
+                .long("starttls")
+                .required(false)
+                .help("Enable STARTTLS extension"),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .takes_value(true)
+                .value_name("path")
+                .required(false)
+                .requires("tls_key")
+                .help(
+                    "PEM file with TLS certificate chain. Self-signed one is generated if omitted",
+                ),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("tls-key")
+                .takes_value(true)
+                .value_name("path")
+                .required(false)
+                .requires("tls_cert")
+                .help("PEM file with TLS private key"),
+        )
+        .arg(
+            Arg::with_name("auth")
+                .long("auth")
+                .takes_value(true)
+                .value_name("mode")
+                .required(false)
+                .help("Enable AUTH extension. Mode is 'any', 'fail' or 'file:<path>' with user:password lines"),
+        )
+        .arg(
+            Arg::with_name("oauth")
+                .long("oauth")
+                .takes_value(true)
+                .value_name("mode")
+                .required(false)
+                .help("Enable XOAUTH2 and OAUTHBEARER. Mode is 'any' or 'file:<path>' with token or user:token lines"),
+        )
+        .arg(
+            Arg::with_name("storage")
+                .short("s")
+                .long("storage")
+                .takes_value(true)
+                .value_name("format:path")
+                .required(false)
+                .conflicts_with("maildir")
+                .help("Store accepted emails. Format is one of 'maildir', 'mbox' or 'eml'"),
+        )
+        .arg(
+            Arg::with_name("maildir")
+                .long("maildir")
+                .takes_value(true)
+                .value_name("path")
+                .required(false)
+                .help("Store accepted emails into the Maildir at the given path"),
+        )
+        .arg(
+            Arg::with_name("http")
+                .long("http")
+                .takes_value(true)
+                .value_name("addr")
+                .required(false)
+                .help("Address to serve HTTP API for inspecting received emails"),
+        )
+        .arg(
+            Arg::with_name("store_messages")
+                .long("store-max-messages")
+                .takes_value(true)
+                .default_value("1000")
+                .value_name("num")
+                .required(false)
+                .help("Maximum number of emails kept in memory for HTTP API"),
+        )
+        .arg(
+            Arg::with_name("store_bytes")
+                .long("store-max-bytes")
+                .takes_value(true)
+                .default_value("268435456")
+                .value_name("num")
+                .required(false)
+                .help("Maximum total size of emails kept in memory for HTTP API"),
+        )
         .get_matches();
 
     if let Err(e) = run(&args) {
@@ -266,3 +936,203 @@ fn main() {
         std::process::exit(-1);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::pki_types::{PrivatePkcs8KeyDer, ServerName};
+    use rustls::{ClientConfig, ClientConnection, RootCertStore, ServerConfig, StreamOwned};
+    use std::convert::TryFrom;
+    use std::net::TcpListener;
+
+    fn test_config() -> Config {
+        Config {
+            policy: None,
+            greylist: None,
+            sinks: Vec::new(),
+            tls: None,
+            starttls: false,
+            authenticator: None,
+            token_validator: None,
+            dsn_fail_ratio: None,
+            directory: None,
+            vrfy_disabled: false,
+            lmtp: false,
+            lmtp_fail_ratio: 0f32,
+            xclient_peers: Vec::new(),
+            latency: Latency::default(),
+            stall_timeout: time::Duration::ZERO,
+        }
+    }
+
+    /// Serves a single connection and returns the client side of it with
+    /// the greeting already read.
+    fn connect(config: Config, stat: Arc<Stat>) -> BufReader<TcpStream> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let config = Arc::new(config);
+
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let listener = Listener {
+                addr,
+                implicit_tls: false,
+                proxy: false,
+            };
+            handle_connection(stream, listener, config, stat);
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        stream
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        let mut client = BufReader::new(stream);
+        let mut line = String::new();
+        client.read_line(&mut line).unwrap();
+        assert!(line.starts_with("220 "), "{}", line);
+        client
+    }
+
+    fn read_reply<R: BufRead>(client: &mut R) -> String {
+        let mut reply = String::new();
+        loop {
+            let mut line = String::new();
+            client.read_line(&mut line).unwrap();
+            reply.push_str(&line);
+            if line.len() < 4 || line.as_bytes()[3] != b'-' {
+                return reply;
+            }
+        }
+    }
+
+    #[test]
+    fn handle_connection_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let mut config = test_config();
+        config.sinks.push(store.clone());
+        let mut client = connect(config, Arc::new(Stat::new()));
+
+        client.get_mut().write_all(b"EHLO client\r\n").unwrap();
+        assert!(read_reply(&mut client).contains("250 "));
+
+        // Replies are held while the rest of the pipelined group is still
+        // in the server's buffer.
+        client
+            .get_mut()
+            .write_all(b"MAIL FROM:<a@example.com>\r\nRCPT TO:<b@example.com>\r\nDAT")
+            .unwrap();
+        client
+            .get_ref()
+            .set_read_timeout(Some(time::Duration::from_millis(300)))
+            .unwrap();
+        let mut buf = [0u8; 1024];
+        assert!(client.get_mut().read(&mut buf).is_err());
+
+        client
+            .get_ref()
+            .set_read_timeout(Some(time::Duration::from_secs(5)))
+            .unwrap();
+        client.get_mut().write_all(b"A\r\n").unwrap();
+        let len = client.get_mut().read(&mut buf).unwrap();
+        let replies = String::from_utf8_lossy(&buf[..len]);
+        let statuses: Vec<&str> = replies.lines().map(|line| &line[..4]).collect();
+        assert_eq!(statuses, vec!["250 ", "250 ", "354 "], "{}", replies);
+
+        client
+            .get_mut()
+            .write_all(b"Subject: test\r\n\r\nbody\r\n.\r\nQUIT\r\n")
+            .unwrap();
+        assert!(read_reply(&mut client).starts_with("250 "));
+        assert!(read_reply(&mut client).starts_with("221 "));
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn handle_connection_test2() {
+        let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let cert = certified.cert.der().clone();
+        let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let server = ServerConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_single_cert(vec![cert.clone()], key.into())
+            .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots.add(cert).unwrap();
+        let client_config = ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let mut config = test_config();
+        config.tls = Some(Arc::new(server));
+        config.starttls = true;
+        let mut client = connect(config, Arc::new(Stat::new()));
+
+        client.get_mut().write_all(b"EHLO client\r\n").unwrap();
+        assert!(read_reply(&mut client).contains("STARTTLS"));
+
+        // Commands pipelined after STARTTLS are discarded
+        client
+            .get_mut()
+            .write_all(b"STARTTLS\r\nMAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        assert!(read_reply(&mut client).starts_with("220 "));
+        assert!(client.buffer().is_empty());
+
+        let conn = ClientConnection::new(
+            Arc::new(client_config),
+            ServerName::try_from("localhost").unwrap(),
+        )
+        .unwrap();
+        let mut tls = BufReader::new(StreamOwned::new(conn, client.into_inner()));
+        // The first reply in the TLS session is the one to EHLO
+        tls.get_mut().write_all(b"EHLO client\r\n").unwrap();
+        let reply = read_reply(&mut tls);
+        assert!(reply.starts_with("250-") && !reply.contains("STARTTLS"));
+        // RCPT is out of sequence, since MAIL has never been processed
+        tls.get_mut()
+            .write_all(b"RCPT TO:<b@example.com>\r\n")
+            .unwrap();
+        assert!(read_reply(&mut tls).starts_with("500 "));
+        tls.get_mut().write_all(b"QUIT\r\n").unwrap();
+        assert!(read_reply(&mut tls).starts_with("221 "));
+    }
+
+    #[test]
+    fn handle_connection_test3() {
+        let mut config = test_config();
+        config.lmtp = true;
+        let stat = Arc::new(Stat::new());
+        let mut client = connect(config, stat.clone());
+
+        client.get_mut().write_all(b"LHLO client\r\n").unwrap();
+        assert!(read_reply(&mut client).contains("250 "));
+        client
+            .get_mut()
+            .write_all(
+                b"MAIL FROM:<a@example.com>\r\n\
+RCPT TO:<b@example.com>\r\n\
+RCPT TO:<c@example.com>\r\n\
+DATA\r\n",
+            )
+            .unwrap();
+        for _ in 0..4 {
+            read_reply(&mut client);
+        }
+
+        // One message with two recipients is counted once
+        client
+            .get_mut()
+            .write_all(b"Subject: test\r\n\r\nbody\r\n.\r\n")
+            .unwrap();
+        assert!(read_reply(&mut client).starts_with("250 "));
+        assert!(read_reply(&mut client).starts_with("250 "));
+        assert_eq!(stat.accepted.load(Ordering::SeqCst), 1);
+        assert_eq!(stat.rejected.load(Ordering::SeqCst), 0);
+    }
+}
@@ -1,228 +1,1204 @@
 use anyhow::{anyhow, Error};
 use rand::prelude::*;
-use regex::Regex;
-use std::io::Read;
+use std::cell::Cell;
+use std::io::{self, BufRead, Read};
+use std::net::SocketAddr;
+use std::sync::Arc;
 
+mod address;
 mod command;
+mod params;
+
+pub mod auth;
+pub mod directory;
+pub mod dsn;
+pub mod greylist;
+pub mod mime;
+pub mod policy;
 
 pub mod reply;
 pub mod state;
+pub mod xclient;
 
+use self::address::AddressError;
+use self::auth::{Authenticator, Exchange, TokenValidator};
 use self::command::*;
+use self::directory::{Directory, Entry};
+use self::dsn::{Action, RecipientStatus};
+use self::greylist::Greylist;
+use self::params::Parameters;
+use self::policy::{Context, Fault, Policy, Stage};
 use self::reply::*;
 use self::state::*;
+use self::xclient::{Attributes, XCLIENT_ATTRIBUTES, XFORWARD_ATTRIBUTES};
+use crate::storage::{Envelope, MessageSink};
 
-static HOSTNAME: &str = "fakesmtpd";
-static MESSAGE_BODY_TERMINATOR: &[u8] = &[b'\r', b'\n', b'.', b'\r', b'\n'];
+pub static HOSTNAME: &str = "fakesmtpd";
 static INITIAL_MESSAGE_BUFFER_SIZE: usize = 1024 * 1024;
 static MAX_EMAIL_SIZE: usize = 73_400_320;
 static MAX_RECIPIENTS_COUNT: usize = 500;
 
 lazy_static! {
-    static ref MAIL_COMMAND_REGEX: Regex =
-        Regex::new("(?i:From):\\s*<(?P<email>[^>]*)>(\\s+(?i:Size)=(?P<size>\\d+))?").unwrap();
-    static ref RCPT_COMMAND_REGEX: Regex = Regex::new("(?i:To):\\s*<(?P<email>[^>]+)>").unwrap();
     static ref SIZE: String = format!("SIZE {}", MAX_EMAIL_SIZE);
-    static ref EHLO_MESSAGE: Vec<&'static str> = vec![HOSTNAME, SIZE.as_str(), "8BITMIME"];
+    static ref EHLO_MESSAGE: Vec<&'static str> = vec![
+        HOSTNAME,
+        SIZE.as_str(),
+        "8BITMIME",
+        "BINARYMIME",
+        "CHUNKING",
+        "DSN",
+        "ENHANCEDSTATUSCODES",
+        "PIPELINING",
+        "SMTPUTF8"
+    ];
     static ref GREETING_MESSAGE: String = format!("{} ESMTP ready", HOSTNAME);
+    static ref MAILBOX_FULL: String = Reply::mailbox_full().to_string();
+    static ref LMTP_GREETING_MESSAGE: String = format!("{} LMTP ready", HOSTNAME);
 }
 
 #[derive(Debug, Default)]
 pub struct Protocol {
     pub message: Vec<u8>,
-    pub buffer: Box<[u8]>,
     pub state: State,
+    #[allow(dead_code)]
     pub last_command: Command,
     pub from: String,
     pub recipients: Vec<String>,
 
-    reject_ratio: f32,
+    policy: Option<Arc<Policy>>,
+    greylist: Option<Arc<Greylist>>,
+    /// Fault to inject instead of the reply to the last command.
+    fault: Cell<Option<Fault>>,
+    peer_addr: Option<SocketAddr>,
+    sinks: Vec<Arc<dyn MessageSink>>,
+    esmtp: bool,
+    helo: String,
+    tls_available: bool,
+    tls_active: bool,
+    authenticator: Option<Arc<Authenticator>>,
+    token_validator: Option<Arc<dyn TokenValidator>>,
+    auth_mechanisms: String,
+    authenticated: bool,
+    /// Identity the client authenticated as. OAUTHBEARER clients may
+    /// authenticate without one.
+    auth_user: Option<String>,
+    auth_exchange: Option<Exchange>,
+    auth_challenge: String,
+    help_commands: String,
+    /// Message size declared with MAIL command.
+    declared_size: Option<usize>,
+    binarymime: bool,
+    smtputf8: bool,
+    lmtp: bool,
+    lmtp_fail_ratio: f32,
+    directory: Option<Arc<Directory>>,
+    vrfy_disabled: bool,
+    mailboxes: Vec<String>,
+    dsn_fail_ratio: Option<f32>,
+    dsn_mail: dsn::MailParams,
+    dsn_recipients: Vec<dsn::RcptParams>,
+    dsn_rejected: Vec<RecipientStatus>,
+    chunk: Option<Chunk>,
+    xclient_allowed: bool,
+    xclient: Option<Attributes>,
+    xforward: Option<Attributes>,
+}
+
+/// Chunk announced by BDAT command which has not been read yet.
+#[derive(Debug)]
+struct Chunk {
+    size: usize,
+    last: bool,
+    /// Error reply sent after the chunk is read and discarded.
+    error: Option<Reply<'static>>,
+    /// State to return to after the chunk is read.
+    state: State,
 }
 
 impl Protocol {
     pub fn new() -> Self {
         Protocol {
             message: Vec::with_capacity(INITIAL_MESSAGE_BUFFER_SIZE),
-            buffer: vec![0u8; INITIAL_MESSAGE_BUFFER_SIZE].into_boxed_slice(),
             ..Default::default()
         }
     }
 
-    pub fn set_reject_ratio(&mut self, ratio: f32) {
-        self.reject_ratio = ratio;
+    /// Sets rules deciding which commands are rejected and how.
+    pub fn set_policy(&mut self, policy: Arc<Policy>) {
+        self.policy = Some(policy);
+    }
+
+    pub fn set_peer_addr(&mut self, peer_addr: SocketAddr) {
+        self.peer_addr = Some(peer_addr);
+    }
+
+    pub fn add_sink(&mut self, sink: Arc<dyn MessageSink>) {
+        self.sinks.push(sink);
+    }
+
+    /// Switches the session to LMTP (RFC 2033). Must be called before
+    /// start().
+    pub fn set_lmtp(&mut self, lmtp: bool) {
+        self.lmtp = lmtp;
+    }
+
+    /// Sets probability of delivery to a recipient failing in LMTP mode,
+    /// which is reported by its own reply after the message.
+    pub fn set_lmtp_fail_ratio(&mut self, ratio: f32) {
+        self.lmtp_fail_ratio = ratio;
+    }
+
+    /// Sets users and aliases VRFY and EXPN are answered from.
+    pub fn set_directory(&mut self, directory: Arc<Directory>) {
+        self.directory = Some(directory);
+    }
+
+    /// Makes VRFY always reply with 252 and disables EXPN, the way most
+    /// public servers behave.
+    pub fn set_vrfy_disabled(&mut self, disabled: bool) {
+        self.vrfy_disabled = disabled;
+    }
+
+    /// Enables generation of delivery status notifications. Accepted
+    /// recipients fail after the end of the transaction with the given
+    /// probability.
+    pub fn set_dsn_fail_ratio(&mut self, ratio: f32) {
+        self.dsn_fail_ratio = Some(ratio);
+    }
+
+    /// Enables greylisting of recipients with the table shared by all
+    /// sessions.
+    pub fn set_greylist(&mut self, greylist: Arc<Greylist>) {
+        self.greylist = Some(greylist);
+    }
+
+    /// Allows the client to override its attributes with XCLIENT and
+    /// XFORWARD commands. Only trusted proxies and content filters should
+    /// be allowed to.
+    pub fn set_xclient_allowed(&mut self, allowed: bool) {
+        self.xclient_allowed = allowed;
+    }
+
+    /// Enables STARTTLS extension for the session.
+    pub fn set_tls_available(&mut self, available: bool) {
+        self.tls_available = available;
+    }
+
+    /// Enables AUTH extension for the session.
+    pub fn set_authenticator(&mut self, authenticator: Arc<Authenticator>) {
+        self.authenticator = Some(authenticator);
+        self.update_auth_mechanisms();
+    }
+
+    /// Enables XOAUTH2 and OAUTHBEARER mechanisms of AUTH extension.
+    pub fn set_token_validator(&mut self, validator: Arc<dyn TokenValidator>) {
+        self.token_validator = Some(validator);
+        self.update_auth_mechanisms();
+    }
+
+    fn update_auth_mechanisms(&mut self) {
+        let mut mechanisms = vec!["AUTH"];
+        if self.authenticator.is_some() {
+            mechanisms.push(auth::PASSWORD_MECHANISMS);
+        }
+        if self.token_validator.is_some() {
+            mechanisms.push(auth::OAUTH_MECHANISMS);
+        }
+        self.auth_mechanisms = mechanisms.join(" ");
+    }
+
+    pub fn is_starttls(&self) -> bool {
+        self.state == State::StartTls
+    }
+
+    /// Must be called once TLS handshake has completed. Per RFC 3207 all
+    /// knowledge obtained from the client before is discarded and the
+    /// client has to start over with EHLO.
+    pub fn tls_started(&mut self) {
+        self.cleanup();
+        self.esmtp = false;
+        self.authenticated = false;
+        self.auth_user = None;
+        self.tls_active = true;
+        self.state = State::Establish;
     }
 
     pub fn is_data(&self) -> bool {
         self.state == State::Data
     }
 
+    pub fn is_chunk(&self) -> bool {
+        self.state == State::Chunk
+    }
+
+    pub fn is_last_chunk(&self) -> bool {
+        matches!(self.chunk, Some(Chunk { last: true, .. }))
+    }
+
+    /// Returns fault the connection is to be broken with before sending the
+    /// last reply, if a policy rule has decided so.
+    pub fn take_fault(&mut self) -> Option<Fault> {
+        self.fault.take()
+    }
+
     pub fn is_done(&self) -> bool {
         self.state == State::Done
     }
 
-    pub fn start(&mut self) -> Reply {
+    pub fn start(&mut self) -> Reply<'_> {
+        if let Some(reply) = self.check_policy(Stage::Connect, &[], None, None) {
+            // The client is expected to QUIT after 554 greeting (RFC 5321,
+            // section 3.1), so only commands allowed in any state are
+            // accepted.
+            self.state = State::Invalid;
+            return self.reject(reply);
+        }
+
         self.state = State::Establish;
+        let greeting = if self.lmtp {
+            LMTP_GREETING_MESSAGE.as_str()
+        } else {
+            GREETING_MESSAGE.as_str()
+        };
         Reply {
             status: 220,
-            lines: vec![GREETING_MESSAGE.as_str()],
+            enhanced_status: None,
+            lines: vec![greeting],
         }
     }
 
-    pub fn process_command(&mut self, line: &str) -> Result<Reply, Error> {
+    pub fn process_command(&mut self, line: &str) -> Result<Reply<'_>, Error> {
+        if self.state == State::Auth {
+            return Ok(self.auth_response(line.trim_end_matches("\r\n")));
+        }
+
         match parse_command(line.trim_end_matches("\r\n")) {
             Ok(cmd) => Ok(self.command(&cmd)),
             Err(err) => Err(err),
         }
     }
 
-    pub fn process_data<R>(&mut self, reader: &mut R) -> Result<Reply, Error>
+    /// Reads message content up to the terminating line with a single dot.
+    /// Reading is done line by line, so commands pipelined by the client
+    /// after the message stay in the reader's buffer. In LMTP mode there is
+    /// a reply for every recipient, otherwise the only one.
+    pub fn process_data<R>(&mut self, reader: &mut R) -> Result<Vec<Reply<'_>>, Error>
     where
-        R: Read,
+        R: BufRead,
     {
+        let mut line = Vec::new();
+        let mut too_big = false;
+        let mut line_start = true;
+
         loop {
-            if let Ok(bytes_read) = reader.read(&mut self.buffer) {
-                if bytes_read > 0 {
-                    let m = &mut self.message;
-                    if m.len() + bytes_read > MAX_EMAIL_SIZE {
-                        return Ok(Reply::message_too_big());
-                    }
-                    m.extend_from_slice(&(*self.buffer)[0..bytes_read]);
-                    if m.len() >= MESSAGE_BODY_TERMINATOR.len() {
-                        let idx = m.len() - MESSAGE_BODY_TERMINATOR.len();
-                        if &m[idx..] == MESSAGE_BODY_TERMINATOR {
-                            m.truncate(idx + 2);
-                            self.state = State::Mail;
-                            break;
-                        }
-                    }
-                } else {
-                    return Err(anyhow!("client closed connection"));
-                }
+            // Lines are read in pieces no longer than the space left in the
+            // message, so a client cannot make the buffer grow unbounded.
+            let limit = if too_big {
+                INITIAL_MESSAGE_BUFFER_SIZE
             } else {
-                return Err(anyhow!("data read error"));
+                MAX_EMAIL_SIZE - self.message.len() + 3
+            };
+            line.clear();
+            match reader
+                .by_ref()
+                .take(limit as u64)
+                .read_until(b'\n', &mut line)
+            {
+                Ok(0) => return Err(anyhow!("client closed connection")),
+                Ok(_) => {}
+                Err(_) => return Err(anyhow!("data read error")),
             }
+
+            // Only a CRLF line break starts a new line, so the dot after
+            // a bare LF is message content (RFC 5321, section 4.1.1.4).
+            let at_line_start = line_start;
+            line_start = line.ends_with(b"\r\n");
+
+            if at_line_start && line == b".\r\n" {
+                self.state = State::Mail;
+                break;
+            }
+
+            // Remove the leading dot added by the client to every line
+            // starting with a dot (RFC 5321, section 4.5.2).
+            let content = if at_line_start && line.starts_with(b".") {
+                &line[1..]
+            } else {
+                &line[..]
+            };
+
+            if self.message.len() + content.len() > MAX_EMAIL_SIZE {
+                too_big = true;
+            }
+            if !too_big {
+                self.message.extend_from_slice(content);
+            }
+        }
+
+        if too_big {
+            let count = self.reply_count();
+            self.cleanup();
+            return Ok((0..count).map(|_| Reply::message_too_big()).collect());
+        }
+
+        Ok(self.complete_message())
+    }
+
+    /// Reads chunk announced by the last BDAT command (RFC 3030). The chunk
+    /// is read even if BDAT was rejected, so its content is never taken
+    /// for commands.
+    pub fn process_chunk<R>(&mut self, reader: &mut R) -> Result<Vec<Reply<'_>>, Error>
+    where
+        R: Read,
+    {
+        let chunk = self
+            .chunk
+            .take()
+            .ok_or_else(|| anyhow!("no chunk expected"))?;
+        let mut data = reader.take(chunk.size as u64);
+
+        let read = match chunk.error {
+            Some(_) => io::copy(&mut data, &mut io::sink()),
+            None => data.read_to_end(&mut self.message).map(|len| len as u64),
+        };
+        match read {
+            Ok(len) if len < chunk.size as u64 => return Err(anyhow!("client closed connection")),
+            Ok(_) => {}
+            Err(_) => return Err(anyhow!("chunk read error")),
         }
 
+        self.state = chunk.state;
+        match chunk.error {
+            Some(reply) => Ok(vec![reply]),
+            None if chunk.last => Ok(self.complete_message()),
+            None => Ok(vec![Reply::ok("Ok")]),
+        }
+    }
+
+    /// Delivers received message to the sinks and resets the transaction.
+    fn complete_message(&mut self) -> Vec<Reply<'static>> {
         debug!(
             "received mail to {:?}, size: {}",
             self.recipients,
             self.message.len()
         );
 
+        if let Some(reply) = self.check_policy(
+            Stage::Eod,
+            &self.recipients,
+            Some(self.message.len()),
+            Some(&self.message),
+        ) {
+            let count = self.reply_count();
+            self.cleanup();
+            let reply = self.reject(reply);
+            return vec![reply; count];
+        }
+        // The connection is broken before the message is accepted, unless
+        // the reply is lost on the way to the client.
+        if matches!(
+            self.fault.get(),
+            Some(Fault::Close | Fault::Reset | Fault::Shutdown)
+        ) {
+            let count = self.reply_count();
+            self.cleanup();
+            return vec![Reply::local_error(); count];
+        }
+
+        // In LMTP mode delivery to every recipient succeeds or fails on its
+        // own (RFC 2033, section 4.2). Failed recipients are dropped from
+        // the envelope of the stored message.
+        let failed: Vec<bool> = (0..self.reply_count())
+            .map(|_| self.lmtp && random::<f32>() < self.lmtp_fail_ratio)
+            .collect();
+        if self.lmtp {
+            let mut failed_iter = failed.iter();
+            self.recipients.retain(|_| !failed_iter.next().unwrap());
+            let mut failed_iter = failed.iter();
+            self.dsn_recipients.retain(|_| !failed_iter.next().unwrap());
+        }
+
+        let reply = if self.recipients.is_empty() {
+            Reply::ok("Ok")
+        } else {
+            match self.store() {
+                Ok(()) => {
+                    if let Err(err) = self.send_dsn() {
+                        error!("failed to store delivery status notification: {}", err);
+                    }
+                    Reply::ok("Ok")
+                }
+                Err(err) => {
+                    error!("failed to store message: {}", err);
+                    Reply::local_error()
+                }
+            }
+        };
+
         self.cleanup();
 
-        Ok(Reply::ok("Ok"))
+        failed
+            .into_iter()
+            .map(|failed| {
+                if failed {
+                    Reply::mailbox_full()
+                } else {
+                    reply.clone()
+                }
+            })
+            .collect()
+    }
+
+    /// Number of replies sent after the message.
+    fn reply_count(&self) -> usize {
+        if self.lmtp {
+            self.recipients.len()
+        } else {
+            1
+        }
     }
 
-    pub fn command(&mut self, command: &Command) -> Reply {
+    pub fn command(&mut self, command: &Command) -> Reply<'_> {
         match command.verb.as_ref() {
             "QUIT" => {
                 self.state = State::Done;
                 Reply::bye()
             }
             "NOOP" => Reply::ok("Ok"),
-            "RSET" => {
+            "RSET" if self.state != State::Invalid => {
                 self.cleanup();
                 self.state = State::Mail;
                 Reply::ok("Ok")
             }
-            "EHLO" if self.state == State::Establish => self.ehlo(),
-            "EHLO" if self.state == State::Mail => self.ehlo(),
-            "EHLO" if self.state == State::Rcpt => self.ehlo(),
-            "HELO" if self.state == State::Establish => self.helo(),
-            "HELO" if self.state == State::Mail => self.helo(),
-            "HELO" if self.state == State::Rcpt => self.helo(),
+            "EHLO" | "HELO" if self.lmtp => self.invalid_command(),
+            "LHLO" if self.lmtp && self.state == State::Establish => self.ehlo(command),
+            "LHLO" if self.lmtp && self.state == State::Mail => self.ehlo(command),
+            "LHLO" if self.lmtp && self.state == State::Rcpt => self.ehlo(command),
+            "EHLO" if self.state == State::Establish => self.ehlo(command),
+            "EHLO" if self.state == State::Mail => self.ehlo(command),
+            "EHLO" if self.state == State::Rcpt => self.ehlo(command),
+            "HELO" if self.state == State::Establish => self.helo(command),
+            "HELO" if self.state == State::Mail => self.helo(command),
+            "HELO" if self.state == State::Rcpt => self.helo(command),
             "MAIL" if self.state == State::Mail => self.mail(command),
             "RCPT" if self.state == State::Rcpt => self.rcpt(command),
             "DATA" if self.state == State::Rcpt && !self.recipients.is_empty() => self.data(),
+            "BDAT" => self.bdat(command),
+            "VRFY" => self.vrfy(command),
+            "EXPN" => self.expn(command),
+            "HELP" => self.help(),
+            "XCLIENT" | "XFORWARD" if !self.xclient_allowed => self.invalid_command(),
+            "XCLIENT" if self.state == State::Establish || self.state == State::Mail => {
+                self.xclient(command)
+            }
+            "XFORWARD" if self.state == State::Establish || self.state == State::Mail => {
+                self.xforward(command)
+            }
+            "XCLIENT" | "XFORWARD" => Reply::bad_sequence(),
+            "STARTTLS" if self.state == State::Mail && self.tls_available && !self.tls_active => {
+                self.starttls(command)
+            }
+            "AUTH" if self.state == State::Mail && !self.auth_mechanisms.is_empty() => {
+                self.auth(command)
+            }
             _ => self.invalid_command(),
         }
     }
 
+    fn store(&mut self) -> Result<(), Error> {
+        if self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        let mut envelope = Envelope::new(self.client_addr()?, &self.from, &self.recipients);
+        envelope.protocol = self.protocol();
+        envelope.auth = self.auth_user.clone();
+        envelope.xclient = self.xclient.clone();
+        envelope.xforward = self.xforward.clone();
+        envelope.binary = self.binarymime;
+
+        for sink in &self.sinks {
+            sink.store(&envelope, &self.message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Delivers notification about the recipients rejected during the
+    /// transaction and the ones which failed after the message has been
+    /// accepted. The notification is stored just like received messages.
+    fn send_dsn(&mut self) -> Result<(), Error> {
+        let fail_ratio = match self.dsn_fail_ratio {
+            Some(ratio) => ratio,
+            None => return Ok(()),
+        };
+        // Notifications are never sent to the null reverse-path
+        if self.from.is_empty() || self.sinks.is_empty() {
+            return Ok(());
+        }
+
+        let mut statuses = std::mem::take(&mut self.dsn_rejected);
+        for (recipient, params) in self.recipients.iter().zip(&self.dsn_recipients) {
+            let (action, status, diagnostic) = if random::<f32>() < fail_ratio {
+                (Action::Failed, "5.2.2", MAILBOX_FULL.trim_end())
+            } else {
+                (Action::Delivered, "2.0.0", "250 2.0.0 Ok")
+            };
+            statuses.push(RecipientStatus {
+                recipient: recipient.clone(),
+                params: params.clone(),
+                action,
+                status,
+                diagnostic: diagnostic.to_string(),
+            });
+        }
+        statuses.retain(RecipientStatus::is_requested);
+
+        if statuses.is_empty() {
+            return Ok(());
+        }
+
+        let envelope = Envelope::new(self.client_addr()?, "", std::slice::from_ref(&self.from));
+        let report = dsn::report(
+            &self.from,
+            envelope.received_at,
+            &self.dsn_mail,
+            &statuses,
+            &self.message,
+        );
+
+        for sink in &self.sinks {
+            sink.store(&envelope, &report)?;
+        }
+
+        Ok(())
+    }
+
+    /// Address of the client the message is received from. It is the peer
+    /// address unless overridden with XCLIENT.
+    fn client_addr(&self) -> Result<SocketAddr, Error> {
+        let peer_addr = self
+            .peer_addr
+            .ok_or_else(|| anyhow!("peer address is unknown"))?;
+
+        match self.xclient {
+            Some(Attributes {
+                addr: Some(addr),
+                port,
+                ..
+            }) => Ok(SocketAddr::new(addr, port.unwrap_or_default())),
+            _ => Ok(peer_addr),
+        }
+    }
+
+    /// Checks the policy rules of the stage against the current session and
+    /// returns the reply of the rule rejecting the command. Fault of the
+    /// rule is kept to be taken with take_fault().
+    fn check_policy(
+        &self,
+        stage: Stage,
+        recipients: &[String],
+        size: Option<usize>,
+        message: Option<&[u8]>,
+    ) -> Option<Reply<'static>> {
+        let policy = self.policy.as_ref()?;
+        let context = Context {
+            peer: self.client_addr().ok().map(|addr| addr.ip()),
+            helo: &self.helo,
+            from: &self.from,
+            recipients,
+            size,
+            message,
+        };
+
+        match policy.check(stage, &context)? {
+            policy::Action::Reject(reply) => Some(reply),
+            policy::Action::Fault(fault) => {
+                self.fault.set(Some(fault));
+                None
+            }
+        }
+    }
+
+    /// Closes the session after the policy reply with 421 code (RFC 5321,
+    /// section 3.8).
+    fn reject(&mut self, reply: Reply<'static>) -> Reply<'static> {
+        if reply.status == 421 {
+            self.cleanup();
+            self.state = State::Done;
+        }
+        reply
+    }
+
     fn cleanup(&mut self) {
         self.message.clear();
         self.recipients.clear();
         self.from.clear();
+        self.declared_size = None;
+        self.binarymime = false;
+        self.smtputf8 = false;
+        self.dsn_mail = dsn::MailParams::default();
+        self.dsn_recipients.clear();
+        self.dsn_rejected.clear();
+        self.xforward = None;
     }
 
-    fn invalid_command(&mut self) -> Reply {
+    fn invalid_command(&mut self) -> Reply<'_> {
         Reply::unknown_command()
     }
 
-    fn ehlo(&mut self) -> Reply {
+    /// Protocol name for the `Received` header as registered in RFC 3848
+    /// and RFC 6531 (section 3.7.3).
+    fn protocol(&self) -> &'static str {
+        let names = match (self.lmtp, self.smtputf8) {
+            (false, false) if !self.esmtp => return "SMTP",
+            (false, false) => ["ESMTP", "ESMTPA", "ESMTPS", "ESMTPSA"],
+            (false, true) => ["UTF8SMTP", "UTF8SMTPA", "UTF8SMTPS", "UTF8SMTPSA"],
+            (true, false) => ["LMTP", "LMTPA", "LMTPS", "LMTPSA"],
+            (true, true) => ["UTF8LMTP", "UTF8LMTPA", "UTF8LMTPS", "UTF8LMTPSA"],
+        };
+        names[self.tls_active as usize * 2 + self.authenticated as usize]
+    }
+
+    fn ehlo(&mut self, cmd: &Command) -> Reply<'_> {
+        self.helo = cmd.args.clone();
+        if let Some(reply) = self.check_policy(Stage::Helo, &[], None, None) {
+            return self.reject(reply);
+        }
+
         self.state = State::Mail;
-        Reply::ok_many(EHLO_MESSAGE.to_vec())
+        self.esmtp = true;
+
+        let mut lines = EHLO_MESSAGE.to_vec();
+        if self.tls_available && !self.tls_active {
+            lines.push("STARTTLS");
+        }
+        if !self.auth_mechanisms.is_empty() {
+            lines.push(self.auth_mechanisms.as_str());
+        }
+        if self.xclient_allowed {
+            lines.push(XCLIENT_ATTRIBUTES);
+            lines.push(XFORWARD_ATTRIBUTES);
+        }
+
+        Reply::ok_many(lines)
     }
 
-    fn helo(&mut self) -> Reply {
+    fn helo(&mut self, cmd: &Command) -> Reply<'_> {
+        self.helo = cmd.args.clone();
+        if let Some(reply) = self.check_policy(Stage::Helo, &[], None, None) {
+            return self.reject(reply);
+        }
+
         self.state = State::Mail;
-        Reply::ok(HOSTNAME)
+        self.esmtp = false;
+        Reply::hello(HOSTNAME)
     }
 
-    fn mail(&mut self, cmd: &Command) -> Reply {
-        self.state = State::Rcpt;
-        let cap = MAIL_COMMAND_REGEX.captures(cmd.args.as_str());
-
-        match cap {
-            Some(cap) => {
-                let addr = cap.name("email").map(|email| email.as_str());
-                let size = cap.name("size").map(|size| size.as_str());
-                match addr {
-                    Some(address) => {
-                        self.from = address.to_string();
-                        if let Some(size) = size {
-                            let size = size.parse::<usize>();
-                            match size {
-                                Ok(size) if size > MAX_EMAIL_SIZE => Reply::message_too_big(),
-                                Err(err) => {
-                                    error!("'FROM' command parameter parse error: {}", err);
-                                    Reply::unknown_command()
-                                }
-                                _ => Reply::ok("Ok"),
-                            }
-                        } else {
-                            Reply::ok("Ok")
-                        }
+    /// Lists commands available in the session, the same way EHLO
+    /// advertises extensions.
+    fn help(&mut self) -> Reply<'_> {
+        let mut commands = if self.lmtp {
+            vec!["LHLO"]
+        } else {
+            vec!["HELO", "EHLO"]
+        };
+        commands.extend([
+            "MAIL", "RCPT", "DATA", "BDAT", "RSET", "NOOP", "QUIT", "VRFY",
+        ]);
+        if self.directory.is_some() && !self.vrfy_disabled {
+            commands.push("EXPN");
+        }
+        commands.push("HELP");
+        if self.tls_available && !self.tls_active {
+            commands.push("STARTTLS");
+        }
+        if !self.auth_mechanisms.is_empty() {
+            commands.push("AUTH");
+        }
+        if self.xclient_allowed {
+            commands.extend(["XCLIENT", "XFORWARD"]);
+        }
+
+        self.help_commands = commands.join(" ");
+        Reply::help(&self.help_commands)
+    }
+
+    /// Replaces attributes of the client for the rest of the session. The
+    /// session starts over with the greeting, as if the client has just
+    /// connected (Postfix XCLIENT extension).
+    fn xclient(&mut self, cmd: &Command) -> Reply<'_> {
+        let mut attributes = self.xclient.clone().unwrap_or_default();
+        if attributes.update(&cmd.args, XCLIENT_ATTRIBUTES).is_err() {
+            return Reply::invalid_parameter();
+        }
+
+        self.cleanup();
+        self.esmtp = false;
+        self.authenticated = attributes.login.is_some();
+        self.auth_user = attributes.login.clone();
+        self.xclient = Some(attributes);
+        self.start()
+    }
+
+    /// Records attributes of the original client for the next transaction
+    /// (Postfix XFORWARD extension).
+    fn xforward(&mut self, cmd: &Command) -> Reply<'_> {
+        let mut attributes = self.xforward.clone().unwrap_or_default();
+        if attributes.update(&cmd.args, XFORWARD_ATTRIBUTES).is_err() {
+            return Reply::invalid_parameter();
+        }
+
+        self.xforward = Some(attributes);
+        Reply::ok("Ok")
+    }
+
+    fn starttls(&mut self, cmd: &Command) -> Reply<'_> {
+        if !cmd.args.is_empty() {
+            return Reply::syntax_error();
+        }
+        self.state = State::StartTls;
+        Reply::ready_to_start_tls()
+    }
+
+    fn auth(&mut self, cmd: &Command) -> Reply<'_> {
+        if self.authenticated {
+            return Reply::bad_sequence();
+        }
+
+        let mut args = cmd.args.split_whitespace();
+        let mechanism = args.next().unwrap_or_default().to_uppercase();
+        let initial_response = args.next();
+
+        if args.next().is_some() {
+            return Reply::syntax_error();
+        }
+
+        let password = self.authenticator.is_some();
+        let oauth = self.token_validator.is_some();
+
+        match (mechanism.as_str(), initial_response) {
+            ("PLAIN" | "LOGIN" | "CRAM-MD5", _) if !password => Reply::unsupported_mechanism(),
+            ("XOAUTH2" | "OAUTHBEARER", _) if !oauth => Reply::unsupported_mechanism(),
+            ("XOAUTH2", Some(response)) => self.auth_xoauth2(response),
+            ("XOAUTH2", None) => self.auth_continue(Exchange::XOAuth2, String::new()),
+            ("OAUTHBEARER", Some(response)) => self.auth_oauthbearer(response),
+            ("OAUTHBEARER", None) => self.auth_continue(Exchange::OAuthBearer, String::new()),
+            ("PLAIN", Some(response)) => self.auth_plain(response),
+            ("PLAIN", None) => self.auth_continue(Exchange::Plain, String::new()),
+            ("LOGIN", Some(response)) => self.auth_login_user(response),
+            ("LOGIN", None) => self.auth_continue(
+                Exchange::LoginUser,
+                auth::LOGIN_USERNAME_CHALLENGE.to_string(),
+            ),
+            ("CRAM-MD5", None) => {
+                let challenge = format!(
+                    "<{}.{}@{}>",
+                    random::<u32>(),
+                    chrono::Utc::now().timestamp(),
+                    HOSTNAME
+                );
+                let encoded = auth::encode(&challenge);
+                self.auth_continue(Exchange::CramMd5(challenge), encoded)
+            }
+            ("", _) | ("CRAM-MD5", Some(_)) => Reply::syntax_error(),
+            _ => Reply::unsupported_mechanism(),
+        }
+    }
+
+    fn auth_continue(&mut self, exchange: Exchange, challenge: String) -> Reply<'_> {
+        self.state = State::Auth;
+        self.auth_exchange = Some(exchange);
+        self.auth_challenge = challenge;
+        Reply::auth_challenge(self.auth_challenge.as_str())
+    }
+
+    fn auth_response(&mut self, line: &str) -> Reply<'_> {
+        self.state = State::Mail;
+
+        if line == "*" {
+            self.auth_exchange = None;
+            return Reply::auth_cancelled();
+        }
+
+        match self.auth_exchange.take() {
+            Some(Exchange::Plain) => self.auth_plain(line),
+            Some(Exchange::LoginUser) => self.auth_login_user(line),
+            Some(Exchange::LoginPassword(user)) => {
+                match auth::decode(line).and_then(|password| String::from_utf8(password).ok()) {
+                    Some(password) => {
+                        let valid = self.check_auth(|auth| auth.check(&user, &password));
+                        self.auth_finish(Some(user), valid)
                     }
-                    None => Reply::invalid_address(),
+                    None => Reply::syntax_error(),
                 }
             }
-            None => Reply::invalid_address(),
+            Some(Exchange::CramMd5(challenge)) => {
+                let response = auth::decode(line).and_then(|data| String::from_utf8(data).ok());
+                let (user, digest) = match response
+                    .as_ref()
+                    .and_then(|r| r.rfind(' ').map(|idx| r.split_at(idx)))
+                {
+                    Some((user, digest)) => (user.to_string(), digest.trim().to_string()),
+                    None => return Reply::syntax_error(),
+                };
+                let valid = self.check_auth(|auth| auth.check_cram_md5(&user, &challenge, &digest));
+                self.auth_finish(Some(user), valid)
+            }
+            Some(Exchange::XOAuth2) => self.auth_xoauth2(line),
+            Some(Exchange::OAuthBearer) => self.auth_oauthbearer(line),
+            Some(Exchange::OAuthFailed) => Reply::auth_failed(),
+            None => self.invalid_command(),
+        }
+    }
+
+    fn auth_plain(&mut self, response: &str) -> Reply<'_> {
+        match auth::decode_plain(response) {
+            Some((user, password)) => {
+                let valid = self.check_auth(|auth| auth.check(&user, &password));
+                self.auth_finish(Some(user), valid)
+            }
+            None => Reply::syntax_error(),
+        }
+    }
+
+    fn auth_login_user(&mut self, response: &str) -> Reply<'_> {
+        match auth::decode(response).and_then(|user| String::from_utf8(user).ok()) {
+            Some(user) => self.auth_continue(
+                Exchange::LoginPassword(user),
+                auth::LOGIN_PASSWORD_CHALLENGE.to_string(),
+            ),
+            None => Reply::syntax_error(),
+        }
+    }
+
+    fn auth_xoauth2(&mut self, response: &str) -> Reply<'_> {
+        match auth::decode_xoauth2(response) {
+            Some((user, token)) => {
+                let valid = self.check_token(Some(&user), &token);
+                self.auth_oauth_finish(Exchange::XOAuth2, Some(user), valid)
+            }
+            None => Reply::syntax_error(),
+        }
+    }
+
+    fn auth_oauthbearer(&mut self, response: &str) -> Reply<'_> {
+        match auth::decode_oauthbearer(response) {
+            Some((user, token)) => {
+                let valid = self.check_token(user.as_deref(), &token);
+                self.auth_oauth_finish(Exchange::OAuthBearer, user, valid)
+            }
+            None => Reply::syntax_error(),
+        }
+    }
+
+    fn check_token(&self, user: Option<&str>, token: &str) -> bool {
+        self.token_validator
+            .as_ref()
+            .map(|validator| validator.validate(user, token))
+            .unwrap_or(false)
+    }
+
+    /// Unlike other mechanisms failed OAuth authentication is reported with
+    /// a JSON error challenge first.
+    fn auth_oauth_finish(
+        &mut self,
+        mechanism: Exchange,
+        user: Option<String>,
+        valid: bool,
+    ) -> Reply<'_> {
+        if valid {
+            self.auth_finish(user, true)
+        } else {
+            let error = auth::oauth_error(&mechanism);
+            self.auth_continue(Exchange::OAuthFailed, error)
+        }
+    }
+
+    fn check_auth<F>(&self, check: F) -> bool
+    where
+        F: FnOnce(&Authenticator) -> bool,
+    {
+        self.authenticator.as_deref().map(check).unwrap_or(false)
+    }
+
+    /// The identity ends up in the Received header, so identities with
+    /// control characters are refused whatever the credentials.
+    fn auth_finish(&mut self, user: Option<String>, valid: bool) -> Reply<'_> {
+        if user.iter().any(|user| user.chars().any(char::is_control)) {
+            Reply::syntax_error()
+        } else if valid {
+            debug!("authenticated as {:?}", user);
+            self.authenticated = true;
+            self.auth_user = user;
+            Reply::auth_success()
+        } else {
+            Reply::auth_failed()
         }
     }
 
-    fn rcpt(&mut self, cmd: &Command) -> Reply {
+    fn mail(&mut self, cmd: &Command) -> Reply<'_> {
+        let (address, params) = match address::parse_path(&cmd.args, "FROM:") {
+            Ok((address, _)) if !address.is_empty() && !address.contains('@') => {
+                return Reply::invalid_address();
+            }
+            Ok(path) => path,
+            Err(err) => return address_error(err),
+        };
+        let params = match Parameters::parse(params) {
+            Ok(params) => params,
+            Err(err) => {
+                error!("'{}' command parameter parse error: {}", cmd.origin, err);
+                return Reply::syntax_error();
+            }
+        };
+        self.from = address;
+
+        let mut size = None;
+        let mut binarymime = false;
+        let mut smtputf8 = false;
+
+        for &(keyword, value) in params.iter() {
+            match (keyword.to_ascii_uppercase().as_str(), value) {
+                ("SIZE", Some(value)) => match value.parse::<usize>() {
+                    Ok(value) => size = Some(value),
+                    Err(_) => return Reply::invalid_parameter(),
+                },
+                ("BODY", Some(value)) if value.eq_ignore_ascii_case("BINARYMIME") => {
+                    binarymime = true;
+                }
+                ("BODY", Some(value))
+                    if value.eq_ignore_ascii_case("7BIT")
+                        || value.eq_ignore_ascii_case("8BITMIME") => {}
+                ("SMTPUTF8", None) => smtputf8 = true,
+                // Validated together below
+                ("RET", Some(_)) | ("ENVID", Some(_)) => {}
+                // Authorization identity (RFC 4954, section 5) is not used
+                ("AUTH", Some(_)) if !self.auth_mechanisms.is_empty() => {}
+                ("SIZE" | "BODY" | "SMTPUTF8" | "RET" | "ENVID", _) => {
+                    return Reply::invalid_parameter();
+                }
+                _ => return Reply::unknown_parameter(),
+            }
+        }
+
+        if let Err(err) = address::validate(&self.from, smtputf8) {
+            return address_error(err);
+        }
+        match dsn::MailParams::parse(params.value("RET"), params.value("ENVID")) {
+            Ok(dsn) => self.dsn_mail = dsn,
+            Err(err) => {
+                error!("'{}' command parameter parse error: {}", cmd.origin, err);
+                return Reply::invalid_parameter();
+            }
+        }
+        if size.is_some_and(|size| size > MAX_EMAIL_SIZE) {
+            return Reply::message_too_big();
+        }
+        if let Some(reply) = self.check_policy(Stage::Mail, &[], size, None) {
+            return self.reject(reply);
+        }
+
+        self.declared_size = size;
+        self.binarymime = binarymime;
+        self.smtputf8 = smtputf8;
         self.state = State::Rcpt;
-        let m = RCPT_COMMAND_REGEX
-            .captures(cmd.args.as_str())
-            .and_then(|cap| cap.name("email").map(|email| email.as_str()));
-        match m {
-            Some(_address) if self.recipients.len() >= MAX_RECIPIENTS_COUNT => {
-                Reply::too_many_recipients()
+        Reply::ok_with("2.1.0", "Ok")
+    }
+
+    fn rcpt(&mut self, cmd: &Command) -> Reply<'_> {
+        let (address, params) = match address::parse_path(&cmd.args, "TO:") {
+            Ok((address, _)) if address.is_empty() => return Reply::invalid_address(),
+            Ok(path) => path,
+            Err(err) => return address_error(err),
+        };
+        let params = match Parameters::parse(params) {
+            Ok(params) => params,
+            Err(err) => {
+                error!("'{}' command parameter parse error: {}", cmd.origin, err);
+                return Reply::syntax_error();
             }
-            Some(_address)
-                if self.reject_ratio > 0f32
-                    && (self.reject_ratio >= 1f32 || random::<f32>() >= self.reject_ratio) =>
-            {
-                Reply::unknown_user()
+        };
+
+        for &(keyword, value) in params.iter() {
+            match (keyword.to_ascii_uppercase().as_str(), value) {
+                ("NOTIFY", Some(_)) | ("ORCPT", Some(_)) => {}
+                ("NOTIFY" | "ORCPT", None) => return Reply::invalid_parameter(),
+                _ => return Reply::unknown_parameter(),
             }
-            Some(address) => {
-                self.recipients.push(address.to_string());
-                Reply::ok("Ok")
+        }
+
+        if let Err(err) = address::validate(&address, self.smtputf8) {
+            return address_error(err);
+        }
+        let dsn = match dsn::RcptParams::parse(params.value("NOTIFY"), params.value("ORCPT")) {
+            Ok(dsn) => dsn,
+            Err(err) => {
+                error!("'{}' command parameter parse error: {}", cmd.origin, err);
+                return Reply::invalid_parameter();
             }
-            None => Reply::invalid_address(),
+        };
+
+        if self.recipients.len() >= MAX_RECIPIENTS_COUNT {
+            return Reply::too_many_recipients();
         }
+
+        if let Some(reply) = self.check_policy(
+            Stage::Rcpt,
+            std::slice::from_ref(&address),
+            self.declared_size,
+            None,
+        ) {
+            // Temporary failures are retried by the client rather than
+            // reported to the sender.
+            if reply.status >= 500 {
+                self.dsn_rejected.push(RecipientStatus {
+                    recipient: address,
+                    params: dsn,
+                    action: Action::Failed,
+                    status: reply.enhanced_status.unwrap_or("5.0.0"),
+                    diagnostic: reply.to_string().trim_end().to_string(),
+                });
+            }
+            return self.reject(reply);
+        }
+
+        if let (Some(greylist), Ok(addr)) = (&self.greylist, self.client_addr()) {
+            if !greylist.check(addr.ip(), &self.from, &address) {
+                return Reply::greylisted();
+            }
+        }
+
+        self.recipients.push(address);
+        self.dsn_recipients.push(dsn);
+        Reply::ok_with("2.1.5", "Ok")
     }
 
-    fn data(&mut self) -> Reply {
+    fn vrfy(&mut self, cmd: &Command) -> Reply<'_> {
+        if cmd.args.is_empty() {
+            return Reply::syntax_error();
+        }
+        let directory = match self.directory {
+            Some(ref directory) if !self.vrfy_disabled => directory.clone(),
+            _ => return Reply::cannot_verify(),
+        };
+
+        self.mailboxes = match directory.lookup(&cmd.args) {
+            Some(Entry::User(name)) | Some(Entry::Alias(name, _)) => vec![format!("<{}>", name)],
+            None => return Reply::unknown_user(),
+        };
+
+        Reply::mailboxes(self.mailboxes.iter().map(String::as_str).collect())
+    }
+
+    fn expn(&mut self, cmd: &Command) -> Reply<'_> {
+        if cmd.args.is_empty() {
+            return Reply::syntax_error();
+        }
+        let directory = match self.directory {
+            Some(ref directory) if !self.vrfy_disabled => directory.clone(),
+            _ => return Reply::not_implemented(),
+        };
+
+        self.mailboxes = match directory.lookup(&cmd.args) {
+            Some(Entry::Alias(_, members)) => members
+                .iter()
+                .map(|member| format!("<{}>", member))
+                .collect(),
+            _ => return Reply::unknown_list(),
+        };
+
+        Reply::mailboxes(self.mailboxes.iter().map(String::as_str).collect())
+    }
+
+    fn data(&mut self) -> Reply<'_> {
+        // BINARYMIME content can only be sent with BDAT (RFC 3030,
+        // section 3).
+        if self.binarymime {
+            return Reply::bad_sequence();
+        }
+        if let Some(reply) =
+            self.check_policy(Stage::Data, &self.recipients, self.declared_size, None)
+        {
+            return self.reject(reply);
+        }
         self.state = State::Data;
         Reply::data()
     }
+
+    fn bdat(&mut self, cmd: &Command) -> Reply<'_> {
+        let mut args = cmd.args.split_whitespace();
+        let size = match args.next().map(str::parse::<usize>) {
+            Some(Ok(size)) => size,
+            _ => return Reply::syntax_error(),
+        };
+        let last = match args.next() {
+            Some(arg) if arg.eq_ignore_ascii_case("LAST") => true,
+            Some(_) => return Reply::syntax_error(),
+            None => false,
+        };
+        if args.next().is_some() {
+            return Reply::syntax_error();
+        }
+
+        let mut error = None;
+        let mut state = State::Chunking;
+        match self.state {
+            State::Rcpt if !self.recipients.is_empty() => {}
+            State::Chunking => {}
+            _ => {
+                error = Some(Reply::bad_sequence());
+                state = std::mem::take(&mut self.state);
+            }
+        }
+        // The first chunk is checked like DATA command
+        if error.is_none() && self.state == State::Rcpt {
+            if let Some(reply) =
+                self.check_policy(Stage::Data, &self.recipients, self.declared_size, None)
+            {
+                error = Some(self.reject(reply));
+                state = std::mem::take(&mut self.state);
+            }
+        }
+        let too_big = self
+            .message
+            .len()
+            .checked_add(size)
+            .is_none_or(|total| total > MAX_EMAIL_SIZE);
+        if error.is_none() && too_big {
+            self.cleanup();
+            error = Some(Reply::message_too_big());
+            state = State::Mail;
+        }
+        if error.is_none() && last {
+            state = State::Mail;
+        }
+
+        self.chunk = Some(Chunk {
+            size,
+            last,
+            error,
+            state,
+        });
+        self.state = State::Chunk;
+
+        // The reply is sent by process_chunk() once the chunk has been read.
+        Reply::default()
+    }
+}
+
+fn address_error(err: AddressError) -> Reply<'static> {
+    match err {
+        AddressError::Invalid => Reply::invalid_address(),
+        AddressError::NonAscii => Reply::non_ascii_address(),
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::storage::memory::MemoryStore;
+    use std::time::Duration;
 
     #[test]
     fn mail_command_test1() {
@@ -268,6 +1244,83 @@ mod tests {
         assert!(reply.status == 556);
     }
 
+    #[test]
+    fn mail_command_test5() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+
+        let reply = smtp.process_command("MAIL FROM:<a b c>\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> X-UNKNOWN=1\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 555);
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> BODY=UTF16\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> SIZE=1 SIZE=2\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp.process_command("MAIL FROM:<postmaster>\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        assert_eq!(smtp.state, State::Mail);
+
+        let reply = smtp
+            .process_command("MAIL FROM:<\"john  smith\"@[192.0.2.1]> BODY=8BITMIME SIZE=10\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.from, "\"john  smith\"@[192.0.2.1]");
+
+        let reply = smtp
+            .process_command("RCPT TO:<b@example.com> SIZE=10\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 555);
+        let reply = smtp.process_command("RCPT TO:<Postmaster>\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+        let reply = smtp
+            .process_command("RCPT TO:<@relay.example:c@example.com>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.recipients, vec!["Postmaster", "c@example.com"]);
+    }
+
+    #[test]
+    fn vrfy_command_test1() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+
+        let reply = smtp.process_command("VRFY alice\r\n").unwrap();
+        assert_eq!(reply.status, 252);
+        let reply = smtp.process_command("EXPN staff\r\n").unwrap();
+        assert_eq!(reply.status, 502);
+        let reply = smtp.process_command("HELP\r\n").unwrap();
+        assert_eq!(reply.status, 214);
+
+        let directory = Directory::parse("alice@example.com\nstaff: alice@example.com, bob\n");
+        smtp.set_directory(Arc::new(directory.unwrap()));
+        let reply = smtp.process_command("VRFY\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp.process_command("VRFY Alice@Example.com\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+        assert_eq!(reply.lines, vec!["<alice@example.com>"]);
+        let reply = smtp.process_command("VRFY carol\r\n").unwrap();
+        assert_eq!(reply.status, 550);
+        let reply = smtp.process_command("EXPN staff\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+        assert_eq!(reply.lines, vec!["<alice@example.com>", "<bob>"]);
+        let reply = smtp.process_command("EXPN alice\r\n").unwrap();
+        assert_eq!(reply.status, 550);
+
+        smtp.set_vrfy_disabled(true);
+        let reply = smtp.process_command("VRFY alice\r\n").unwrap();
+        assert_eq!(reply.status, 252);
+        let reply = smtp.process_command("EXPN staff\r\n").unwrap();
+        assert_eq!(reply.status, 502);
+    }
+
     #[test]
     fn rcpt_command_test1() {
         let mut smtp = Protocol::new();
@@ -298,4 +1351,695 @@ mod tests {
         let reply = smtp.rcpt(&cmd);
         assert!(reply.status > 200);
     }
+
+    #[test]
+    fn smtputf8_test1() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(reply.lines.contains(&"SMTPUTF8"));
+
+        let reply = smtp
+            .process_command("MAIL FROM:<отправитель@пример.рф>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 553);
+        smtp.process_command("RSET\r\n").unwrap();
+
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        let reply = smtp
+            .process_command("RCPT TO:<почта@example.com>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 553);
+        smtp.process_command("RSET\r\n").unwrap();
+
+        let reply = smtp
+            .process_command("MAIL FROM:<отправитель@пример.рф> SMTPUTF8\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        let reply = smtp
+            .process_command("RCPT TO:<почта@пример.рф>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        let reply = smtp
+            .process_command("RCPT TO:<почта@-пример.рф>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        assert_eq!(smtp.recipients, vec!["почта@пример.рф"]);
+        assert_eq!(smtp.protocol(), "UTF8SMTP");
+    }
+
+    #[test]
+    fn dsn_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.set_policy(Arc::new(
+            Policy::parse("rcpt => 550 5.1.1 User unknown").unwrap(),
+        ));
+        smtp.set_dsn_fail_ratio(1f32);
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> RET=FOO\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        smtp.process_command("RSET\r\n").unwrap();
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> ENVID=x+0D+0AX-Injected:+20yes\r\n")
+            .unwrap();
+        assert_eq!((reply.status, reply.enhanced_status), (501, Some("5.5.4")));
+        let reply = smtp
+            .process_command("MAIL FROM:<a@example.com> RET=HDRS ENVID=id+2B1\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        let reply = smtp
+            .process_command("RCPT TO:<b@example.com> NOTIFY=SOMETIMES\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp
+            .process_command("RCPT TO:<b@example.com> ORCPT=rfc822;b@example.com+0D+0A\r\n")
+            .unwrap();
+        assert_eq!((reply.status, reply.enhanced_status), (501, Some("5.5.4")));
+        let reply = smtp
+            .process_command("RCPT TO:<b@example.com> ORCPT=rfc822;b@example.com\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 550);
+        smtp.process_command("RCPT TO:<c@example.com> NOTIFY=NEVER\r\n")
+            .unwrap();
+
+        smtp.set_policy(Arc::new(Policy::default()));
+        smtp.process_command("RCPT TO:<d@example.com> NOTIFY=FAILURE\r\n")
+            .unwrap();
+        smtp.process_command("DATA\r\n").unwrap();
+        let mut input: &[u8] = b"Subject: test\r\n\r\nbody\r\n.\r\n";
+        smtp.process_data(&mut input).unwrap();
+
+        let messages = store.list();
+        assert_eq!(messages.len(), 2);
+        let envelope = &messages[1].envelope;
+        assert_eq!(envelope.from, "");
+        assert_eq!(envelope.recipients, vec!["a@example.com"]);
+
+        let report = String::from_utf8_lossy(&messages[1].data);
+        assert!(report.contains("Original-Envelope-Id: id+1\r\n"));
+        assert!(report.contains("Final-Recipient: rfc822; b@example.com\r\n"));
+        assert!(report.contains("Diagnostic-Code: smtp; 550 5.1.1 User unknown\r\n"));
+        assert!(!report.contains("c@example.com"));
+        assert!(report.contains("Status: 5.2.2\r\n"));
+    }
+
+    #[test]
+    fn process_data_test1() {
+        let store = Arc::new(MemoryStore::new(10, 1024));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.state = State::Data;
+        smtp.recipients.push("test@example.com".to_string());
+
+        let mut input: &[u8] = b"..leading dot\r\nno dot\r\n.\r\nQUIT\r\n";
+        let reply = smtp.process_data(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.state, State::Mail);
+        assert_eq!(input, b"QUIT\r\n");
+        assert!(store.list()[0]
+            .data
+            .ends_with(b"\r\n.leading dot\r\nno dot\r\n"));
+    }
+
+    #[test]
+    fn process_data_test2() {
+        let mut smtp = Protocol::new();
+        smtp.state = State::Data;
+
+        let mut input: &[u8] = b".\r\n";
+        let reply = smtp.process_data(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 250);
+
+        let mut input: &[u8] = b"incomplete\r\n";
+        assert!(smtp.process_data(&mut input).is_err());
+    }
+
+    #[test]
+    fn process_data_test3() {
+        let store = Arc::new(MemoryStore::new(10, 1024));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.state = State::Data;
+        smtp.recipients.push("test@example.com".to_string());
+
+        let mut input: &[u8] = b"bare\n.\r\n..end\r\n.\r\n";
+        let reply = smtp.process_data(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 250);
+        assert!(store.list()[0].data.ends_with(b"\r\nbare\n.\r\n.end\r\n"));
+    }
+
+    #[test]
+    fn process_data_test4() {
+        let mut smtp = Protocol::new();
+        smtp.state = State::Data;
+
+        let mut input = io::repeat(b'x')
+            .take(MAX_EMAIL_SIZE as u64 * 2)
+            .chain(&b"\r\n.\r\nQUIT\r\n"[..]);
+        let mut input = io::BufReader::new(&mut input);
+        let reply = smtp.process_data(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 556);
+        assert_eq!(smtp.state, State::Mail);
+        assert!(smtp.message.capacity() <= MAX_EMAIL_SIZE + 3);
+    }
+
+    #[test]
+    fn lmtp_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.set_lmtp(true);
+
+        let reply = smtp.start();
+        assert_eq!(reply.lines, vec!["fakesmtpd LMTP ready"]);
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert_eq!(reply.status, 500);
+        let reply = smtp.process_command("LHLO client\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        smtp.process_command("RCPT TO:<c@example.com>\r\n").unwrap();
+        smtp.process_command("DATA\r\n").unwrap();
+        let mut input: &[u8] = b"Subject: test\r\n\r\nbody\r\n.\r\n";
+        let replies = smtp.process_data(&mut input).unwrap();
+        assert_eq!(replies.len(), 2);
+        assert!(replies.iter().all(|reply| reply.status == 250));
+        assert_eq!(store.list()[0].envelope.protocol, "LMTP");
+
+        smtp.set_lmtp_fail_ratio(1f32);
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        smtp.process_command("BDAT 4 LAST\r\n").unwrap();
+        let mut input: &[u8] = b"body";
+        let replies = smtp.process_chunk(&mut input).unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].status, 552);
+        assert_eq!(store.list().len(), 1);
+    }
+
+    #[test]
+    fn xclient_command_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.start();
+
+        let reply = smtp.process_command("EHLO relay\r\n").unwrap();
+        assert!(!reply.lines.contains(&XCLIENT_ATTRIBUTES));
+        let reply = smtp.process_command("XCLIENT ADDR=192.0.2.1\r\n").unwrap();
+        assert_eq!(reply.status, 500);
+
+        smtp.set_xclient_allowed(true);
+        let reply = smtp.process_command("EHLO relay\r\n").unwrap();
+        assert!(reply.lines.contains(&XCLIENT_ATTRIBUTES));
+        assert!(reply.lines.contains(&XFORWARD_ATTRIBUTES));
+        let reply = smtp.process_command("XCLIENT BOGUS=1\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp
+            .process_command("XCLIENT NAME=a+0D+0AX-Evil:+20x\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp
+            .process_command("XCLIENT NAME=client.example ADDR=192.0.2.1 PORT=4000 HELO=client\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 220);
+        assert_eq!(smtp.state, State::Establish);
+
+        smtp.process_command("EHLO client\r\n").unwrap();
+        let reply = smtp
+            .process_command("XFORWARD NAME=origin.example ADDR=198.51.100.1 PROTO=ESMTP\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        let reply = smtp.process_command("XFORWARD HELO=origin\r\n").unwrap();
+        assert_eq!(reply.status, 503);
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        smtp.process_command("DATA\r\n").unwrap();
+        let mut input: &[u8] = b"Subject: test\r\n\r\n.\r\n";
+        smtp.process_data(&mut input).unwrap();
+
+        let message = &store.list()[0];
+        assert_eq!(
+            message.envelope.peer_addr,
+            "192.0.2.1:4000".parse().unwrap()
+        );
+        let xclient = message.envelope.xclient.as_ref().unwrap();
+        assert_eq!(xclient.helo.as_deref(), Some("client"));
+        let xforward = message.envelope.xforward.as_ref().unwrap();
+        assert_eq!(xforward.name.as_deref(), Some("origin.example"));
+        assert_eq!(xforward.proto.as_deref(), Some("ESMTP"));
+        assert!(message.data.starts_with(
+            b"Return-Path: <a@example.com>\r\nReceived: from client.example ([192.0.2.1]:4000)"
+        ));
+        assert_eq!(smtp.xforward, None);
+    }
+
+    #[test]
+    fn policy_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let policy = Arc::new(
+            Policy::parse(
+                "connect peer=192.0.2.0/24 => 421 4.7.0 Try again later\n\
+                 helo helo=*.invalid => 550 5.7.1 Bad HELO\n\
+                 rcpt to=*@unknown.example => 550 5.1.1 User unknown\n\
+                 data from=*@slow.example => 451 4.3.0 Try again later\n\
+                 eod header=subject:*spam* => 554 5.7.1 Spam\n",
+            )
+            .unwrap(),
+        );
+
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("192.0.2.1:2525".parse().unwrap());
+        smtp.set_policy(policy.clone());
+        assert_eq!(smtp.start().status, 421);
+        assert!(smtp.is_done());
+
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.set_policy(policy.clone());
+        smtp.add_sink(store.clone());
+        assert_eq!(smtp.start().status, 220);
+        let reply = smtp.process_command("EHLO host.invalid\r\n").unwrap();
+        assert_eq!(reply.status, 550);
+        smtp.process_command("EHLO client\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@slow.example>\r\n")
+            .unwrap();
+        let reply = smtp
+            .process_command("RCPT TO:<b@unknown.example>\r\n")
+            .unwrap();
+        assert_eq!(reply.to_string(), "550 5.1.1 User unknown\r\n");
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        let reply = smtp.process_command("DATA\r\n").unwrap();
+        assert_eq!(reply.status, 451);
+        let reply = smtp.process_command("BDAT 1 LAST\r\n").unwrap();
+        assert_eq!(reply.status, 0);
+        let mut input: &[u8] = b"x";
+        let reply = smtp.process_chunk(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 451);
+        assert_eq!(smtp.state, State::Rcpt);
+
+        smtp.process_command("RSET\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        smtp.process_command("DATA\r\n").unwrap();
+        let mut input: &[u8] = b"Subject: Cheap spam\r\n\r\n.\r\n";
+        let reply = smtp.process_data(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 554);
+        assert_eq!(smtp.state, State::Mail);
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn policy_test2() {
+        let policy = Arc::new(
+            Policy::parse(
+                "rcpt to=*@small.example size>1000 => 552 5.2.2 Mailbox full\n\
+                 data size>2000 => 552 5.3.4 Message too big\n",
+            )
+            .unwrap(),
+        );
+
+        let mut smtp = Protocol::new();
+        smtp.set_policy(policy);
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com> SIZE=1500\r\n")
+            .unwrap();
+        let reply = smtp
+            .process_command("RCPT TO:<b@small.example>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 552);
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        let reply = smtp.process_command("DATA\r\n").unwrap();
+        assert_eq!(reply.status, 354);
+        let mut input: &[u8] = b".\r\n";
+        smtp.process_data(&mut input).unwrap();
+
+        smtp.process_command("RSET\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com> SIZE=2500\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        let reply = smtp.process_command("DATA\r\n").unwrap();
+        assert_eq!(reply.status, 552);
+
+        smtp.process_command("RSET\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        let reply = smtp
+            .process_command("RCPT TO:<b@small.example>\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 250);
+    }
+
+    #[test]
+    fn fault_test1() {
+        let store = Arc::new(MemoryStore::new(10, 4096));
+        let mut policy = Policy::default();
+        policy.add_fault("rcpt=partial").unwrap();
+        policy.add_fault("eod=reset").unwrap();
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.set_policy(Arc::new(policy));
+        smtp.start();
+        assert_eq!(smtp.take_fault(), None);
+
+        smtp.process_command("EHLO client\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        let reply = smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.take_fault(), Some(Fault::Partial));
+        assert_eq!(smtp.take_fault(), None);
+
+        smtp.process_command("DATA\r\n").unwrap();
+        let mut input: &[u8] = b"Subject: test\r\n\r\n.\r\n";
+        smtp.process_data(&mut input).unwrap();
+        assert_eq!(smtp.take_fault(), Some(Fault::Reset));
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn greylist_test1() {
+        let greylist = Arc::new(Greylist::new(
+            Duration::ZERO,
+            Duration::from_secs(60),
+            Duration::from_secs(60),
+        ));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.set_greylist(greylist);
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+
+        let reply = smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        assert_eq!(
+            reply.to_string(),
+            "451 4.7.1 Greylisted, please try again later\r\n"
+        );
+        assert!(smtp.recipients.is_empty());
+        let reply = smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        assert_eq!(reply.status, 250);
+    }
+
+    #[test]
+    fn bdat_command_test1() {
+        let store = Arc::new(MemoryStore::new(10, 1024));
+        let mut smtp = Protocol::new();
+        smtp.set_peer_addr("127.0.0.1:2525".parse().unwrap());
+        smtp.add_sink(store.clone());
+        smtp.start();
+
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(reply.lines.contains(&"CHUNKING"));
+        smtp.process_command("MAIL FROM:<a@example.com> BODY=BINARYMIME\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        let reply = smtp.process_command("DATA\r\n").unwrap();
+        assert_eq!(reply.status, 503);
+
+        let reply = smtp.process_command("BDAT 6\r\n").unwrap();
+        assert!(reply.lines.is_empty());
+        assert!(smtp.is_chunk());
+        assert!(!smtp.is_last_chunk());
+        let mut input: &[u8] = b"\r\n.\r\n\0BDAT 0 LAST\r\n";
+        let reply = smtp.process_chunk(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.state, State::Chunking);
+
+        let reply = smtp.process_command("RCPT TO:<c@example.com>\r\n").unwrap();
+        assert_eq!(reply.status, 500);
+        smtp.process_command("BDAT 0 LAST\r\n").unwrap();
+        assert!(smtp.is_last_chunk());
+        let reply = smtp.process_chunk(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 250);
+        assert_eq!(smtp.state, State::Mail);
+        assert!(store.list()[0].data.ends_with(b"\r\n\r\n.\r\n\0"));
+        assert!(store.list()[0].envelope.binary);
+    }
+
+    #[test]
+    fn bdat_command_test2() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+
+        // Chunk is discarded when BDAT is out of sequence
+        smtp.process_command("BDAT 4 LAST\r\n").unwrap();
+        let mut input: &[u8] = b"MAILNOOP\r\n";
+        let reply = smtp.process_chunk(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 503);
+        assert_eq!(smtp.state, State::Mail);
+        assert_eq!(input, b"NOOP\r\n");
+
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        let reply = smtp.process_command("BDAT 1 MORE\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        smtp.process_command(&format!("BDAT {}\r\n", MAX_EMAIL_SIZE + 1))
+            .unwrap();
+        let mut input = io::repeat(b'x').take(MAX_EMAIL_SIZE as u64 + 1);
+        let reply = smtp.process_chunk(&mut input).unwrap().remove(0);
+        assert_eq!(reply.status, 556);
+        assert_eq!(smtp.state, State::Mail);
+        assert!(smtp.recipients.is_empty());
+
+        // Total size of the chunks overflows
+        smtp.process_command("MAIL FROM:<a@example.com>\r\n")
+            .unwrap();
+        smtp.process_command("RCPT TO:<b@example.com>\r\n").unwrap();
+        smtp.process_command("BDAT 4\r\n").unwrap();
+        let mut input: &[u8] = b"test";
+        smtp.process_chunk(&mut input).unwrap();
+        smtp.process_command(&format!("BDAT {}\r\n", usize::MAX))
+            .unwrap();
+        assert!(smtp.is_chunk());
+        let chunk = smtp.chunk.as_ref().unwrap();
+        assert_eq!(chunk.error.as_ref().map(|reply| reply.status), Some(556));
+        assert_eq!(chunk.state, State::Mail);
+        assert!(smtp.recipients.is_empty());
+    }
+
+    #[test]
+    fn help_command_test1() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        let reply = smtp.process_command("HELP\r\n").unwrap();
+        assert_eq!(reply.status, 214);
+        assert_eq!(
+            reply.lines[1],
+            "HELO EHLO MAIL RCPT DATA BDAT RSET NOOP QUIT VRFY HELP"
+        );
+
+        let directory = Directory::parse("alice@example.com\n").unwrap();
+        smtp.set_directory(Arc::new(directory));
+        smtp.set_tls_available(true);
+        smtp.set_authenticator(Arc::new(Authenticator::Any));
+        smtp.set_xclient_allowed(true);
+        let reply = smtp.process_command("HELP\r\n").unwrap();
+        assert_eq!(
+            reply.lines[1],
+            "HELO EHLO MAIL RCPT DATA BDAT RSET NOOP QUIT VRFY EXPN HELP STARTTLS AUTH \
+             XCLIENT XFORWARD"
+        );
+
+        smtp.set_vrfy_disabled(true);
+        smtp.tls_started();
+        smtp.set_lmtp(true);
+        let reply = smtp.process_command("HELP\r\n").unwrap();
+        assert_eq!(
+            reply.lines[1],
+            "LHLO MAIL RCPT DATA BDAT RSET NOOP QUIT VRFY HELP AUTH XCLIENT XFORWARD"
+        );
+    }
+
+    #[test]
+    fn starttls_command_test1() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(!reply.lines.contains(&"STARTTLS"));
+        let reply = smtp.process_command("STARTTLS\r\n").unwrap();
+        assert_eq!(reply.status, 500);
+
+        smtp.set_tls_available(true);
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(reply.lines.contains(&"STARTTLS"));
+        let reply = smtp.process_command("STARTTLS now\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        let reply = smtp.process_command("STARTTLS\r\n").unwrap();
+        assert_eq!(reply.status, 220);
+        assert!(smtp.is_starttls());
+
+        smtp.tls_started();
+        assert_eq!(smtp.state, State::Establish);
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(!reply.lines.contains(&"STARTTLS"));
+        assert_eq!(smtp.protocol(), "ESMTPS");
+    }
+
+    #[test]
+    fn auth_command_test1() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.process_command("EHLO client\r\n").unwrap();
+        let reply = smtp
+            .process_command("AUTH PLAIN AHVzZXIAc2VjcmV0\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 500);
+
+        smtp.set_authenticator(Arc::new(Authenticator::Fail));
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(reply.lines.contains(&"AUTH PLAIN LOGIN CRAM-MD5"));
+        let reply = smtp
+            .process_command("AUTH PLAIN AHVzZXIAc2VjcmV0\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 535);
+
+        smtp.set_authenticator(Arc::new(Authenticator::Any));
+        let reply = smtp.process_command("AUTH XOAUTH\r\n").unwrap();
+        assert_eq!(reply.status, 504);
+        let reply = smtp.process_command("AUTH XOAUTH2\r\n").unwrap();
+        assert_eq!(reply.status, 504);
+        let reply = smtp.process_command("AUTH PLAIN\r\n").unwrap();
+        assert_eq!(reply.status, 334);
+        let reply = smtp.process_command("AHVzZXIAc2VjcmV0\r\n").unwrap();
+        assert_eq!(reply.status, 235);
+        assert_eq!(smtp.auth_user.as_deref(), Some("user"));
+        assert_eq!(smtp.protocol(), "ESMTPA");
+
+        let reply = smtp
+            .process_command("AUTH PLAIN AHVzZXIAc2VjcmV0\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 503);
+    }
+
+    #[test]
+    fn auth_command_test2() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.set_authenticator(Arc::new(Authenticator::Any));
+        smtp.process_command("EHLO client\r\n").unwrap();
+
+        let reply = smtp.process_command("AUTH LOGIN\r\n").unwrap();
+        assert_eq!((reply.status, reply.lines[0]), (334, "VXNlcm5hbWU6"));
+        let reply = smtp.process_command("dXNlcg==\r\n").unwrap();
+        assert_eq!((reply.status, reply.lines[0]), (334, "UGFzc3dvcmQ6"));
+        let reply = smtp.process_command("*\r\n").unwrap();
+        assert_eq!(reply.status, 501);
+        assert_eq!(smtp.state, State::Mail);
+
+        let reply = smtp.process_command("AUTH LOGIN dXNlcg==\r\n").unwrap();
+        assert_eq!(reply.status, 334);
+        let reply = smtp.process_command("c2VjcmV0\r\n").unwrap();
+        assert_eq!(reply.status, 235);
+
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.set_authenticator(Arc::new(Authenticator::Any));
+        smtp.process_command("EHLO client\r\n").unwrap();
+        let response = auth::encode("\0user\r\nX-Injected: yes\0secret");
+        let reply = smtp
+            .process_command(&format!("AUTH PLAIN {}\r\n", response))
+            .unwrap();
+        assert_eq!((reply.status, reply.enhanced_status), (501, Some("5.5.2")));
+        assert_eq!(smtp.auth_user, None);
+    }
+
+    #[test]
+    fn auth_command_test3() {
+        let mut credentials = std::collections::HashMap::new();
+        credentials.insert("user".to_string(), "secret".to_string());
+
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.set_authenticator(Arc::new(Authenticator::Credentials(credentials)));
+        smtp.process_command("EHLO client\r\n").unwrap();
+
+        let reply = smtp.process_command("AUTH CRAM-MD5\r\n").unwrap();
+        assert_eq!(reply.status, 334);
+        let challenge = String::from_utf8(auth::decode(reply.lines[0]).unwrap()).unwrap();
+
+        let digest = auth::cram_md5_digest("wrong", &challenge);
+        let response = auth::encode(&format!("user {}", digest));
+        let reply = smtp.process_command(&format!("{}\r\n", response)).unwrap();
+        assert_eq!(reply.status, 535);
+
+        let reply = smtp.process_command("AUTH CRAM-MD5\r\n").unwrap();
+        let challenge = String::from_utf8(auth::decode(reply.lines[0]).unwrap()).unwrap();
+        let digest = auth::cram_md5_digest("secret", &challenge);
+        let response = auth::encode(&format!("user {}", digest));
+        let reply = smtp.process_command(&format!("{}\r\n", response)).unwrap();
+        assert_eq!(reply.status, 235);
+    }
+
+    #[test]
+    fn auth_command_test4() {
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.set_token_validator(Arc::new(auth::StaticTokens::parse("user@example.com:good")));
+        let reply = smtp.process_command("EHLO client\r\n").unwrap();
+        assert!(reply.lines.contains(&"AUTH XOAUTH2 OAUTHBEARER"));
+
+        let reply = smtp
+            .process_command("AUTH PLAIN AHVzZXIAc2VjcmV0\r\n")
+            .unwrap();
+        assert_eq!(reply.status, 504);
+
+        let bad = auth::encode("user=user@example.com\x01auth=Bearer bad\x01\x01");
+        let reply = smtp
+            .process_command(&format!("AUTH XOAUTH2 {}\r\n", bad))
+            .unwrap();
+        assert_eq!(reply.status, 334);
+        let error = String::from_utf8(auth::decode(reply.lines[0]).unwrap()).unwrap();
+        assert!(error.contains("\"status\":\"401\""));
+        let reply = smtp.process_command("\r\n").unwrap();
+        assert_eq!(reply.status, 535);
+
+        let reply = smtp.process_command("AUTH OAUTHBEARER\r\n").unwrap();
+        assert_eq!(reply.status, 334);
+        let good = auth::encode("n,a=user@example.com,\x01auth=Bearer good\x01\x01");
+        let reply = smtp.process_command(&format!("{}\r\n", good)).unwrap();
+        assert_eq!(reply.status, 235);
+        assert_eq!(smtp.auth_user.as_deref(), Some("user@example.com"));
+
+        let mut smtp = Protocol::new();
+        smtp.start();
+        smtp.set_token_validator(Arc::new(auth::AcceptAllTokens));
+        smtp.process_command("EHLO client\r\n").unwrap();
+        let token = auth::encode("n,,\x01auth=Bearer any\x01\x01");
+        let reply = smtp
+            .process_command(&format!("AUTH OAUTHBEARER {}\r\n", token))
+            .unwrap();
+        assert_eq!(reply.status, 235);
+        assert_eq!(smtp.auth_user, None);
+        assert_eq!(smtp.protocol(), "ESMTPA");
+        let reply = smtp
+            .process_command(&format!("AUTH OAUTHBEARER {}\r\n", token))
+            .unwrap();
+        assert_eq!(reply.status, 503);
+    }
 }
@@ -0,0 +1,383 @@
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Reason the mailbox given in MAIL or RCPT command is not accepted.
+#[derive(Debug, PartialEq)]
+pub enum AddressError {
+    /// Local part or domain is malformed.
+    Invalid,
+    /// Address contains non-ASCII characters, but the client did not
+    /// request SMTPUTF8 (RFC 6531, section 3.2).
+    NonAscii,
+}
+
+/// Validates internationalized mailbox `local-part@domain`. UTF-8 local
+/// parts are accepted as is, non-ASCII domains must be valid IDNs.
+pub fn validate(address: &str, smtputf8: bool) -> Result<(), AddressError> {
+    if address.is_ascii() {
+        return Ok(());
+    }
+    if !smtputf8 {
+        return Err(AddressError::NonAscii);
+    }
+
+    let (local, domain) = match address.rfind('@') {
+        Some(pos) => (&address[..pos], Some(&address[pos + 1..])),
+        None => (address, None),
+    };
+
+    if local.is_empty() || local.chars().any(char::is_control) {
+        return Err(AddressError::Invalid);
+    }
+
+    match domain {
+        Some(domain) if !domain.is_ascii() => idna::domain_to_ascii_strict(domain)
+            .map(|_| ())
+            .map_err(|_| AddressError::Invalid),
+        _ => Ok(()),
+    }
+}
+
+/// Validates host name or address literal (`Domain / address-literal` in
+/// RFC 5321), e.g. given in HELO command.
+pub fn validate_host(host: &str) -> Result<(), AddressError> {
+    let mut parser = Parser::new(host);
+    if parser.peek() == Some('[') {
+        parser.address_literal()?;
+    } else {
+        parser.domain()?;
+    }
+
+    if parser.rest().is_empty() {
+        Ok(())
+    } else {
+        Err(AddressError::Invalid)
+    }
+}
+
+/// Parses argument of MAIL (`FROM:<reverse-path> [parameters]`) or RCPT
+/// (`TO:<forward-path> [parameters]`) command as defined in RFC 5321,
+/// section 4.1.2, and returns the mailbox and the parameters string.
+///
+/// The mailbox is empty for the null path `<>` and has no domain for
+/// `<postmaster>`. Source route is accepted but dropped. Non-ASCII
+/// characters are allowed in the local part and the domain (RFC 6531) and
+/// have to be checked with [`validate`].
+pub fn parse_path<'a>(args: &'a str, prefix: &str) -> Result<(String, &'a str), AddressError> {
+    match args.get(..prefix.len()) {
+        Some(head) if head.eq_ignore_ascii_case(prefix) => {}
+        _ => return Err(AddressError::Invalid),
+    }
+
+    // Space after the colon is not allowed by the RFC but is sent by
+    // enough clients to be tolerated.
+    let mut parser = Parser::new(args[prefix.len()..].trim_start());
+    let mailbox = parser.path()?;
+    let rest = parser.rest();
+
+    if !rest.is_empty() && !rest.starts_with(' ') {
+        return Err(AddressError::Invalid);
+    }
+
+    Ok((mailbox, rest.trim()))
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Parser { input, pos: 0 }
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), AddressError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            _ => Err(AddressError::Invalid),
+        }
+    }
+
+    fn take_while<F>(&mut self, predicate: F) -> &'a str
+    where
+        F: Fn(char) -> bool,
+    {
+        let start = self.pos;
+        while self.peek().is_some_and(&predicate) {
+            self.next();
+        }
+        &self.input[start..self.pos]
+    }
+
+    /// Path = "<" [ A-d-l ":" ] Mailbox ">" / "<>"
+    fn path(&mut self) -> Result<String, AddressError> {
+        self.expect('<')?;
+
+        if self.peek() == Some('>') {
+            self.next();
+            return Ok(String::new());
+        }
+
+        if self.peek() == Some('@') {
+            self.source_route()?;
+        }
+
+        let start = self.pos;
+        let local = self.local_part()?;
+
+        if self.peek() == Some('>') {
+            self.next();
+            if local.eq_ignore_ascii_case("postmaster") {
+                return Ok(local.to_string());
+            }
+            return Err(AddressError::Invalid);
+        }
+
+        self.expect('@')?;
+        if self.peek() == Some('[') {
+            self.address_literal()?;
+        } else {
+            self.domain()?;
+        }
+        let mailbox = &self.input[start..self.pos];
+        self.expect('>')?;
+
+        Ok(mailbox.to_string())
+    }
+
+    /// A-d-l = At-domain *( "," At-domain ) ":"
+    fn source_route(&mut self) -> Result<(), AddressError> {
+        loop {
+            self.expect('@')?;
+            self.domain()?;
+            match self.next() {
+                Some(',') => continue,
+                Some(':') => return Ok(()),
+                _ => return Err(AddressError::Invalid),
+            }
+        }
+    }
+
+    /// Local-part = Dot-string / Quoted-string
+    fn local_part(&mut self) -> Result<&'a str, AddressError> {
+        let start = self.pos;
+
+        if self.peek() == Some('"') {
+            self.next();
+            loop {
+                match self.next() {
+                    Some('"') => break,
+                    Some('\\') => match self.next() {
+                        Some(c) if is_quoted_pair(c) => {}
+                        _ => return Err(AddressError::Invalid),
+                    },
+                    Some(c) if is_qtext(c) => {}
+                    _ => return Err(AddressError::Invalid),
+                }
+            }
+        } else {
+            loop {
+                if self.take_while(is_atext).is_empty() {
+                    return Err(AddressError::Invalid);
+                }
+                if self.peek() != Some('.') {
+                    break;
+                }
+                self.next();
+            }
+        }
+
+        Ok(&self.input[start..self.pos])
+    }
+
+    /// Domain = sub-domain *("." sub-domain)
+    fn domain(&mut self) -> Result<(), AddressError> {
+        loop {
+            let label = self.take_while(|c| c.is_ascii_alphanumeric() || c == '-' || !c.is_ascii());
+            if label.is_empty() || label.starts_with('-') || label.ends_with('-') {
+                return Err(AddressError::Invalid);
+            }
+            if self.peek() != Some('.') {
+                return Ok(());
+            }
+            self.next();
+        }
+    }
+
+    /// address-literal = "[" ( IPv4-address-literal / IPv6-address-literal /
+    /// General-address-literal ) "]"
+    fn address_literal(&mut self) -> Result<(), AddressError> {
+        self.expect('[')?;
+        let literal = self.take_while(|c| c != ']' && c.is_ascii_graphic());
+        self.expect(']')?;
+
+        let valid = match literal.find(':') {
+            None => literal.parse::<Ipv4Addr>().is_ok(),
+            Some(pos) if literal[..pos].eq_ignore_ascii_case("IPv6") => {
+                literal[pos + 1..].parse::<Ipv6Addr>().is_ok()
+            }
+            Some(pos) => {
+                let tag = &literal[..pos];
+                let content = &literal[pos + 1..];
+                !tag.is_empty()
+                    && tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                    && !tag.ends_with('-')
+                    && !content.is_empty()
+                    && !content.contains(['[', '\\'])
+            }
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(AddressError::Invalid)
+        }
+    }
+}
+
+/// atext (RFC 5322) extended with non-ASCII characters (RFC 6531).
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+-/=?^_`{|}~".contains(c) || !c.is_ascii()
+}
+
+/// qtextSMTP extended with non-ASCII characters.
+fn is_qtext(c: char) -> bool {
+    matches!(c, ' ' | '!' | '#'..='[' | ']'..='~') || (!c.is_ascii() && !c.is_control())
+}
+
+fn is_quoted_pair(c: char) -> bool {
+    matches!(c, ' '..='~')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn validate_test1() {
+        assert_eq!(validate("user@example.com", false), Ok(()));
+        assert_eq!(validate("", false), Ok(()));
+        assert_eq!(
+            validate("пользователь@example.com", false),
+            Err(AddressError::NonAscii)
+        );
+        assert_eq!(
+            validate("user@пример.рф", false),
+            Err(AddressError::NonAscii)
+        );
+        assert_eq!(validate("пользователь@пример.рф", true), Ok(()));
+        assert_eq!(validate("用户@例子.广告", true), Ok(()));
+        assert_eq!(validate("почтмейстер", true), Ok(()));
+        assert_eq!(
+            validate("user@пример..рф", true),
+            Err(AddressError::Invalid)
+        );
+        assert_eq!(
+            validate("user@-пример.рф", true),
+            Err(AddressError::Invalid)
+        );
+        assert_eq!(
+            validate("\u{7f}ü@example.com", true),
+            Err(AddressError::Invalid)
+        );
+    }
+
+    #[test]
+    fn validate_host_test1() {
+        for host in [
+            "mail.example.com",
+            "localhost",
+            "[192.0.2.1]",
+            "[IPv6:2001:db8::1]",
+        ] {
+            assert_eq!(validate_host(host), Ok(()), "{}", host);
+        }
+        for host in [
+            "",
+            "mail example",
+            "-mail.example",
+            "a..b",
+            "[192.0.2.1",
+            "a\r\nb",
+        ] {
+            assert_eq!(validate_host(host), Err(AddressError::Invalid), "{}", host);
+        }
+    }
+
+    #[test]
+    fn parse_path_test1() {
+        let parse = |args| parse_path(args, "FROM:");
+        assert_eq!(
+            parse("FROM:<user@example.com>"),
+            Ok(("user@example.com".to_string(), ""))
+        );
+        assert_eq!(
+            parse("from: <user@example.com> SIZE=100  BODY=8BITMIME"),
+            Ok(("user@example.com".to_string(), "SIZE=100  BODY=8BITMIME"))
+        );
+        assert_eq!(parse("FROM:<>"), Ok((String::new(), "")));
+        assert_eq!(
+            parse("FROM:<@a.example,@b.example:user@example.com>"),
+            Ok(("user@example.com".to_string(), ""))
+        );
+        assert_eq!(
+            parse("FROM:<\"john \\\"doe\\\" smith\"@example.com>"),
+            Ok(("\"john \\\"doe\\\" smith\"@example.com".to_string(), ""))
+        );
+        assert_eq!(
+            parse("FROM:<user@[192.0.2.1]>"),
+            Ok(("user@[192.0.2.1]".to_string(), ""))
+        );
+        assert_eq!(
+            parse("FROM:<user@[IPv6:2001:db8::1]>"),
+            Ok(("user@[IPv6:2001:db8::1]".to_string(), ""))
+        );
+        assert_eq!(
+            parse("FROM:<пользователь@пример.рф>"),
+            Ok(("пользователь@пример.рф".to_string(), ""))
+        );
+        assert_eq!(
+            parse_path("TO:<Postmaster>", "TO:"),
+            Ok(("Postmaster".to_string(), ""))
+        );
+    }
+
+    #[test]
+    fn parse_path_test2() {
+        let parse = |args| parse_path(args, "FROM:");
+        for args in [
+            "TO:<user@example.com>",
+            "FROM:user@example.com",
+            "FROM:<a b c>",
+            "FROM:<user>",
+            "FROM:<user@example.com",
+            "FROM:<user@example.com>SIZE=1",
+            "FROM:<user..name@example.com>",
+            "FROM:<.user@example.com>",
+            "FROM:<user@-example.com>",
+            "FROM:<user@example..com>",
+            "FROM:<user@>",
+            "FROM:<@example.com>",
+            "FROM:<user@[192.0.2.256]>",
+            "FROM:<user@[IPv6:192.0.2.1]>",
+            "FROM:<\"unterminated@example.com>",
+            "FROM:<@a.example user@example.com>",
+        ] {
+            assert_eq!(parse(args), Err(AddressError::Invalid), "{}", args);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use anyhow::{anyhow, Error};
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use md5::Md5;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::sync::Arc;
+
+pub static PASSWORD_MECHANISMS: &str = "PLAIN LOGIN CRAM-MD5";
+pub static OAUTH_MECHANISMS: &str = "XOAUTH2 OAUTHBEARER";
+
+/// Base64 encoded "Username:" and "Password:" prompts of the LOGIN mechanism.
+pub static LOGIN_USERNAME_CHALLENGE: &str = "VXNlcm5hbWU6";
+pub static LOGIN_PASSWORD_CHALLENGE: &str = "UGFzc3dvcmQ6";
+
+/// Step of a multi-line AUTH exchange the server waits a response for.
+#[derive(Debug)]
+pub enum Exchange {
+    Plain,
+    LoginUser,
+    LoginPassword(String),
+    CramMd5(String),
+    XOAuth2,
+    OAuthBearer,
+    /// Error challenge was sent, the client must respond before the
+    /// exchange fails (RFC 7628, section 3.2.3).
+    OAuthFailed,
+}
+
+#[derive(Debug)]
+pub enum Authenticator {
+    /// Any credentials are accepted.
+    Any,
+    /// Only credentials listed in the credentials file are accepted.
+    Credentials(HashMap<String, String>),
+    /// Authentication always fails.
+    Fail,
+}
+
+impl Authenticator {
+    /// Creates authenticator from the `any`, `fail` or `file:<path>`
+    /// specification given on the command line.
+    pub fn parse(spec: &str) -> Result<Self, Error> {
+        match spec {
+            "any" => Ok(Authenticator::Any),
+            "fail" => Ok(Authenticator::Fail),
+            _ if spec.starts_with("file:") => {
+                let content = fs::read_to_string(&spec["file:".len()..])?;
+                Ok(Authenticator::Credentials(parse_credentials(&content)?))
+            }
+            _ => Err(anyhow!("unknown authentication mode '{}'", spec)),
+        }
+    }
+
+    pub fn check(&self, user: &str, password: &str) -> bool {
+        match self {
+            Authenticator::Any => true,
+            Authenticator::Credentials(credentials) => {
+                credentials.get(user).map(String::as_str) == Some(password)
+            }
+            Authenticator::Fail => false,
+        }
+    }
+
+    /// Verifies CRAM-MD5 digest which is HMAC-MD5 of the challenge keyed
+    /// with the user's password.
+    pub fn check_cram_md5(&self, user: &str, challenge: &str, digest: &str) -> bool {
+        match self {
+            Authenticator::Any => true,
+            Authenticator::Credentials(credentials) => match credentials.get(user) {
+                Some(password) => cram_md5_digest(password, challenge) == digest.to_lowercase(),
+                None => false,
+            },
+            Authenticator::Fail => false,
+        }
+    }
+}
+
+/// Validates OAuth 2.0 bearer tokens presented with XOAUTH2 or OAUTHBEARER.
+pub trait TokenValidator: fmt::Debug + Send + Sync {
+    fn validate(&self, user: Option<&str>, token: &str) -> bool;
+}
+
+#[derive(Debug)]
+pub struct AcceptAllTokens;
+
+impl TokenValidator for AcceptAllTokens {
+    fn validate(&self, _user: Option<&str>, _token: &str) -> bool {
+        true
+    }
+}
+
+/// Accepts only listed tokens, optionally bound to a user.
+#[derive(Debug, Default)]
+pub struct StaticTokens {
+    tokens: HashMap<String, Option<String>>,
+}
+
+impl StaticTokens {
+    /// Parses tokens file with one `token` or `user:token` entry per line.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(content: &str) -> Self {
+        let tokens = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| match line.find(':') {
+                Some(pos) => (line[pos + 1..].to_string(), Some(line[..pos].to_string())),
+                None => (line.to_string(), None),
+            })
+            .collect();
+
+        StaticTokens { tokens }
+    }
+}
+
+impl TokenValidator for StaticTokens {
+    fn validate(&self, user: Option<&str>, token: &str) -> bool {
+        match self.tokens.get(token) {
+            Some(Some(owner)) => user == Some(owner.as_str()),
+            Some(None) => true,
+            None => false,
+        }
+    }
+}
+
+/// Creates token validator from the `any` or `file:<path>` specification
+/// given on the command line.
+pub fn parse_token_validator(spec: &str) -> Result<Arc<dyn TokenValidator>, Error> {
+    match spec {
+        "any" => Ok(Arc::new(AcceptAllTokens)),
+        _ if spec.starts_with("file:") => {
+            let content = fs::read_to_string(&spec["file:".len()..])?;
+            Ok(Arc::new(StaticTokens::parse(&content)))
+        }
+        _ => Err(anyhow!("unknown token validation mode '{}'", spec)),
+    }
+}
+
+/// Parses credentials file with one `user:password` pair per line. Empty
+/// lines and lines starting with `#` are ignored.
+fn parse_credentials(content: &str) -> Result<HashMap<String, String>, Error> {
+    let mut credentials = HashMap::new();
+
+    for (idx, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.find(':') {
+            Some(pos) => {
+                credentials.insert(line[..pos].to_string(), line[pos + 1..].to_string());
+            }
+            None => return Err(anyhow!("credentials file line {}: missing ':'", idx + 1)),
+        }
+    }
+
+    Ok(credentials)
+}
+
+pub fn cram_md5_digest(password: &str, challenge: &str) -> String {
+    let mut mac = Hmac::<Md5>::new_from_slice(password.as_bytes()).expect("any key size is valid");
+    mac.update(challenge.as_bytes());
+
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+pub fn encode(data: &str) -> String {
+    base64::engine::general_purpose::STANDARD.encode(data)
+}
+
+pub fn decode(data: &str) -> Option<Vec<u8>> {
+    base64::engine::general_purpose::STANDARD
+        .decode(data.trim())
+        .ok()
+}
+
+/// Decodes PLAIN mechanism response `[authzid] NUL authcid NUL passwd`
+/// into the user name and password.
+pub fn decode_plain(response: &str) -> Option<(String, String)> {
+    let data = String::from_utf8(decode(response)?).ok()?;
+    let mut items = data.split('\0');
+    let _authzid = items.next()?;
+    let user = items.next()?;
+    let password = items.next()?;
+
+    if items.next().is_some() || user.is_empty() {
+        return None;
+    }
+
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Decodes XOAUTH2 response `user=<user>^Aauth=Bearer <token>^A^A` into
+/// the user name and token.
+pub fn decode_xoauth2(response: &str) -> Option<(String, String)> {
+    let data = String::from_utf8(decode(response)?).ok()?;
+    let mut user = None;
+    let mut token = None;
+
+    for item in data.split('\x01') {
+        if let Some(value) = item.strip_prefix("user=") {
+            user = Some(value.to_string());
+        } else if let Some(value) = item.strip_prefix("auth=") {
+            token = bearer_token(value);
+        }
+    }
+
+    Some((user?, token?))
+}
+
+/// Decodes OAUTHBEARER response (RFC 7628) `n,a=<user>,^A...auth=Bearer
+/// <token>^A^A` into the optional authorization identity and token.
+pub fn decode_oauthbearer(response: &str) -> Option<(Option<String>, String)> {
+    let data = String::from_utf8(decode(response)?).ok()?;
+    let mut items = data.split('\x01');
+    let gs2_header = items.next()?;
+
+    let user = gs2_header
+        .split(',')
+        .find_map(|item| item.strip_prefix("a="))
+        .map(|user| user.replace("=2C", ",").replace("=3D", "="));
+    let token = items.find_map(|item| item.strip_prefix("auth=").and_then(bearer_token))?;
+
+    Some((user, token))
+}
+
+fn bearer_token(value: &str) -> Option<String> {
+    let mut items = value.splitn(2, ' ');
+    match (items.next(), items.next()) {
+        (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("Bearer") => {
+            Some(token.trim().to_string())
+        }
+        _ => None,
+    }
+}
+
+/// Error challenge sent on failed OAuth authentication.
+pub fn oauth_error(mechanism: &Exchange) -> String {
+    let error = match mechanism {
+        Exchange::XOAuth2 => {
+            r#"{"status":"401","schemes":"Bearer","scope":"https://mail.google.com/"}"#
+        }
+        _ => r#"{"status":"invalid_token","schemes":"bearer","scope":"email"}"#,
+    };
+    encode(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_plain_test1() {
+        assert_eq!(
+            decode_plain(&encode("\0user\0secret")),
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert_eq!(
+            decode_plain(&encode("admin\0user\0secret")),
+            Some(("user".to_string(), "secret".to_string()))
+        );
+        assert_eq!(decode_plain(&encode("user\0secret")), None);
+        assert_eq!(decode_plain("not base64!"), None);
+    }
+
+    #[test]
+    fn cram_md5_digest_test1() {
+        // Example from RFC 2195
+        assert_eq!(
+            cram_md5_digest(
+                "tanstaaftanstaaf",
+                "<1896.697170952@postoffice.reston.mci.net>"
+            ),
+            "b913a602c7eda7a495b4e6e7334d3890"
+        );
+    }
+
+    #[test]
+    fn decode_xoauth2_test1() {
+        let response = encode("user=someuser@example.com\x01auth=Bearer ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg\x01\x01");
+        assert_eq!(
+            decode_xoauth2(&response),
+            Some((
+                "someuser@example.com".to_string(),
+                "ya29.vF9dft4qmTc2Nvb3RlckBhdHRhdmlzdGEuY29tCg".to_string()
+            ))
+        );
+        assert_eq!(decode_xoauth2(&encode("user=a\x01\x01")), None);
+    }
+
+    #[test]
+    fn decode_oauthbearer_test1() {
+        let response = encode(
+            "n,a=user@example.com,\x01host=server.example.com\x01port=143\x01auth=Bearer vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==\x01\x01",
+        );
+        assert_eq!(
+            decode_oauthbearer(&response),
+            Some((
+                Some("user@example.com".to_string()),
+                "vF9dft4qmTc2Nvb3RlckBhbHRhdmlzdGEuY29tCg==".to_string()
+            ))
+        );
+        let response = encode("n,,\x01auth=Bearer token\x01\x01");
+        assert_eq!(
+            decode_oauthbearer(&response),
+            Some((None, "token".to_string()))
+        );
+    }
+
+    #[test]
+    fn static_tokens_test1() {
+        let tokens = StaticTokens::parse("# comment\nfree\nuser@example.com:bound\n");
+        assert!(tokens.validate(None, "free"));
+        assert!(tokens.validate(Some("any@example.com"), "free"));
+        assert!(tokens.validate(Some("user@example.com"), "bound"));
+        assert!(!tokens.validate(Some("other@example.com"), "bound"));
+        assert!(!tokens.validate(Some("user@example.com"), "unknown"));
+    }
+
+    #[test]
+    fn check_test1() {
+        let credentials = parse_credentials("# comment\n\nuser:secret\nother:a:b\n").unwrap();
+        let auth = Authenticator::Credentials(credentials);
+        assert!(auth.check("user", "secret"));
+        assert!(auth.check("other", "a:b"));
+        assert!(!auth.check("user", "wrong"));
+        assert!(!auth.check("nobody", "secret"));
+
+        assert!(Authenticator::Any.check("nobody", ""));
+        assert!(!Authenticator::Fail.check("user", "secret"));
+        assert!(parse_credentials("user secret").is_err());
+    }
+}
@@ -3,19 +3,24 @@ use anyhow::{anyhow, Error};
 #[derive(Debug, Default)]
 pub struct Command {
     pub verb: String,
+    /// Arguments as sent by the client without surrounding whitespace.
     pub args: String,
     pub origin: String,
 }
 
 pub fn parse_command(line: &str) -> Result<Command, Error> {
-    let items: Vec<&str> = line.split_whitespace().collect();
+    let trimmed = line.trim();
+    let (verb, args) = match trimmed.find(char::is_whitespace) {
+        Some(pos) => (&trimmed[..pos], trimmed[pos..].trim_start()),
+        None => (trimmed, ""),
+    };
 
-    if items.is_empty() {
+    if verb.is_empty() {
         Err(anyhow!("invalid command"))
     } else {
         let cmd = Command {
-            verb: items[0].to_uppercase(),
-            args: items[1..].join(" "),
+            verb: verb.to_ascii_uppercase(),
+            args: args.to_string(),
             origin: line.to_string(),
         };
 
@@ -44,4 +49,16 @@ mod tests {
         assert_eq!(cmd.args, "");
         assert_eq!(cmd.origin, raw);
     }
+
+    #[test]
+    fn parse_command_test3() {
+        let raw = "rcpt to:<straße@example.com>";
+        let cmd = parse_command(raw).unwrap();
+        assert_eq!(cmd.verb, "RCPT");
+        assert_eq!(cmd.args, "to:<straße@example.com>");
+
+        // Unicode case mapping must not turn it into a valid verb
+        let cmd = parse_command("ſtarttls").unwrap();
+        assert_ne!(cmd.verb, "STARTTLS");
+    }
 }
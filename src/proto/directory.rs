@@ -0,0 +1,124 @@
+use anyhow::{anyhow, Error};
+use std::collections::HashMap;
+use std::fs;
+
+/// Known users and aliases (mailing lists) answered to VRFY and EXPN.
+#[derive(Debug, Default)]
+pub struct Directory {
+    /// Users keyed by the lowercase name.
+    users: HashMap<String, String>,
+    /// Aliases keyed by the lowercase name with the name and members.
+    aliases: HashMap<String, (String, Vec<String>)>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Entry<'a> {
+    User(&'a str),
+    Alias(&'a str, &'a [String]),
+}
+
+impl Directory {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses directory file with one `user` or `alias: member, member...`
+    /// entry per line. Empty lines and lines starting with `#` are ignored.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut directory = Directory::default();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            match line.split_once(':') {
+                Some((name, members)) => {
+                    let members: Vec<String> = members
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|member| !member.is_empty())
+                        .map(String::from)
+                        .collect();
+                    if members.is_empty() {
+                        return Err(anyhow!("directory file line {}: no alias members", idx + 1));
+                    }
+                    let name = name.trim();
+                    directory
+                        .aliases
+                        .insert(name.to_lowercase(), (name.to_string(), members));
+                }
+                None => {
+                    directory
+                        .users
+                        .insert(line.to_lowercase(), line.to_string());
+                }
+            }
+        }
+
+        Ok(directory)
+    }
+
+    /// Looks up user or alias by the VRFY/EXPN argument which is either a
+    /// bare name, a mailbox or `Name <mailbox>`. Mailboxes not listed as is
+    /// are looked up by the local part.
+    pub fn lookup(&self, query: &str) -> Option<Entry<'_>> {
+        let query = match (query.find('<'), query.rfind('>')) {
+            (Some(start), Some(end)) if start < end => &query[start + 1..end],
+            _ => query.trim(),
+        };
+
+        self.find(query).or_else(|| {
+            let pos = query.rfind('@')?;
+            self.find(&query[..pos])
+        })
+    }
+
+    fn find(&self, name: &str) -> Option<Entry<'_>> {
+        let key = name.to_lowercase();
+        if let Some(user) = self.users.get(&key) {
+            return Some(Entry::User(user));
+        }
+        self.aliases
+            .get(&key)
+            .map(|(name, members)| Entry::Alias(name, members))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_test1() {
+        let directory = Directory::parse(
+            "# users\nalice@example.com\nBob\n\nstaff: alice@example.com, bob@example.com\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            directory.lookup("Alice@Example.com"),
+            Some(Entry::User("alice@example.com"))
+        );
+        assert_eq!(directory.lookup("bob"), Some(Entry::User("Bob")));
+        assert_eq!(
+            directory.lookup("Bob Smith <bob@example.com>"),
+            Some(Entry::User("Bob"))
+        );
+        assert_eq!(
+            directory.lookup("staff"),
+            Some(Entry::Alias(
+                "staff",
+                &[
+                    "alice@example.com".to_string(),
+                    "bob@example.com".to_string()
+                ]
+            ))
+        );
+        assert_eq!(directory.lookup("carol"), None);
+        assert_eq!(directory.lookup("alice"), None);
+
+        assert!(Directory::parse("empty:\n").is_err());
+    }
+}
@@ -0,0 +1,328 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Local};
+use rand::prelude::*;
+
+use super::HOSTNAME;
+
+/// Part of the original message returned in the DSN.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Ret {
+    Full,
+    Hdrs,
+}
+
+/// DSN parameters of MAIL command (RFC 3461, section 4).
+#[derive(Debug, Default, PartialEq)]
+pub struct MailParams {
+    pub ret: Option<Ret>,
+    pub envid: Option<String>,
+}
+
+impl MailParams {
+    pub fn parse(ret: Option<&str>, envid: Option<&str>) -> Result<Self, Error> {
+        let ret = match ret {
+            Some(ret) if ret.eq_ignore_ascii_case("FULL") => Some(Ret::Full),
+            Some(ret) if ret.eq_ignore_ascii_case("HDRS") => Some(Ret::Hdrs),
+            Some(ret) => return Err(anyhow!("invalid RET value '{}'", ret)),
+            None => None,
+        };
+        let envid = envid.map(decode_xtext).transpose()?;
+
+        Ok(MailParams { ret, envid })
+    }
+}
+
+/// Conditions the sender wants to be notified on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Notify {
+    pub success: bool,
+    pub failure: bool,
+    pub delay: bool,
+}
+
+impl Default for Notify {
+    /// Without NOTIFY parameter the client is notified on failure and
+    /// delay (RFC 3461, section 4.1).
+    fn default() -> Self {
+        Notify {
+            success: false,
+            failure: true,
+            delay: true,
+        }
+    }
+}
+
+impl Notify {
+    pub fn parse(value: &str) -> Result<Self, Error> {
+        let mut notify = Notify {
+            success: false,
+            failure: false,
+            delay: false,
+        };
+
+        if value.eq_ignore_ascii_case("NEVER") {
+            return Ok(notify);
+        }
+
+        for item in value.split(',') {
+            match item.to_ascii_uppercase().as_str() {
+                "SUCCESS" => notify.success = true,
+                "FAILURE" => notify.failure = true,
+                "DELAY" => notify.delay = true,
+                _ => return Err(anyhow!("invalid NOTIFY value '{}'", value)),
+            }
+        }
+
+        Ok(notify)
+    }
+}
+
+/// DSN parameters of RCPT command (RFC 3461, section 4).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RcptParams {
+    pub notify: Notify,
+    /// Original recipient as `addr-type;address`.
+    pub orcpt: Option<String>,
+}
+
+impl RcptParams {
+    pub fn parse(notify: Option<&str>, orcpt: Option<&str>) -> Result<Self, Error> {
+        let notify = notify.map(Notify::parse).transpose()?.unwrap_or_default();
+        let orcpt = match orcpt {
+            Some(orcpt) => match orcpt.find(';') {
+                Some(pos) if pos > 0 => Some(format!(
+                    "{};{}",
+                    &orcpt[..pos],
+                    decode_xtext(&orcpt[pos + 1..])?
+                )),
+                _ => return Err(anyhow!("invalid ORCPT value '{}'", orcpt)),
+            },
+            None => None,
+        };
+
+        Ok(RcptParams { notify, orcpt })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    Failed,
+    Delivered,
+}
+
+/// Outcome of delivery to a single recipient reported in the DSN.
+#[derive(Debug)]
+pub struct RecipientStatus {
+    pub recipient: String,
+    pub params: RcptParams,
+    pub action: Action,
+    /// Enhanced status code, e.g. `5.1.1`.
+    pub status: &'static str,
+    /// SMTP reply the recipient has failed or was delivered with.
+    pub diagnostic: String,
+}
+
+impl RecipientStatus {
+    /// Whether the sender has asked to be notified about this outcome.
+    pub fn is_requested(&self) -> bool {
+        match self.action {
+            Action::Failed => self.params.notify.failure,
+            Action::Delivered => self.params.notify.success,
+        }
+    }
+}
+
+/// Builds RFC 3464 delivery status notification for the message sent by
+/// `from`.
+pub fn report(
+    from: &str,
+    arrival_date: DateTime<Local>,
+    params: &MailParams,
+    statuses: &[RecipientStatus],
+    message: &[u8],
+) -> Vec<u8> {
+    let failed = statuses
+        .iter()
+        .any(|status| status.action == Action::Failed);
+    let boundary = format!("=_{:016x}", random::<u64>());
+    let now = Local::now();
+
+    let mut report = format!(
+        "From: Mail Delivery System <MAILER-DAEMON@{host}>\r\n\
+         To: <{from}>\r\n\
+         Subject: Delivery Status Notification ({result})\r\n\
+         Date: {date}\r\n\
+         Message-ID: <{id}.{random:08x}@{host}>\r\n\
+         Auto-Submitted: auto-replied\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/report; report-type=delivery-status;\r\n \
+         boundary=\"{boundary}\"\r\n\
+         \r\n\
+         --{boundary}\r\n\
+         Content-Type: text/plain; charset=utf-8\r\n\
+         \r\n",
+        host = HOSTNAME,
+        from = from,
+        result = if failed { "Failure" } else { "Success" },
+        date = now.to_rfc2822(),
+        id = now.timestamp(),
+        random = random::<u32>(),
+        boundary = boundary,
+    );
+
+    for status in statuses {
+        let result = match status.action {
+            Action::Failed => "could not be delivered",
+            Action::Delivered => "was delivered",
+        };
+        report.push_str(&format!(
+            "Your message to <{}> {}: {}\r\n",
+            status.recipient, result, status.diagnostic
+        ));
+    }
+
+    report.push_str(&format!(
+        "\r\n--{}\r\nContent-Type: message/delivery-status\r\n\r\nReporting-MTA: dns; {}\r\n",
+        boundary, HOSTNAME
+    ));
+    if let Some(ref envid) = params.envid {
+        report.push_str(&format!("Original-Envelope-Id: {}\r\n", envid));
+    }
+    report.push_str(&format!("Arrival-Date: {}\r\n", arrival_date.to_rfc2822()));
+
+    for status in statuses {
+        report.push_str("\r\n");
+        if let Some(ref orcpt) = status.params.orcpt {
+            report.push_str(&format!("Original-Recipient: {}\r\n", orcpt));
+        }
+        report.push_str(&format!(
+            "Final-Recipient: rfc822; {}\r\nAction: {}\r\nStatus: {}\r\nDiagnostic-Code: smtp; {}\r\n",
+            status.recipient,
+            match status.action {
+                Action::Failed => "failed",
+                Action::Delivered => "delivered",
+            },
+            status.status,
+            status.diagnostic
+        ));
+    }
+
+    let mut report = report.into_bytes();
+
+    // Only headers are returned unless the sender asked for the full message
+    // (RFC 3461, section 4.3).
+    if params.ret == Some(Ret::Full) {
+        report.extend_from_slice(
+            format!("\r\n--{}\r\nContent-Type: message/rfc822\r\n\r\n", boundary).as_bytes(),
+        );
+        report.extend_from_slice(message);
+    } else {
+        let headers = match message.windows(4).position(|w| w == b"\r\n\r\n") {
+            Some(pos) => &message[..pos + 2],
+            None => message,
+        };
+        report.extend_from_slice(
+            format!(
+                "\r\n--{}\r\nContent-Type: text/rfc822-headers\r\n\r\n",
+                boundary
+            )
+            .as_bytes(),
+        );
+        report.extend_from_slice(headers);
+    }
+    if !report.ends_with(b"\r\n") {
+        report.extend_from_slice(b"\r\n");
+    }
+    report.extend_from_slice(format!("--{}--\r\n", boundary).as_bytes());
+
+    report
+}
+
+/// Decodes `xtext` used for ENVID and ORCPT values where `+XX` stands for
+/// the character with hexadecimal code XX (RFC 3461, section 4). Decoded
+/// value is restricted to printable US-ASCII, so it can't break the header
+/// it is copied to.
+pub(super) fn decode_xtext(value: &str) -> Result<String, Error> {
+    let mut result = Vec::with_capacity(value.len());
+    let mut bytes = value.bytes();
+
+    while let Some(b) = bytes.next() {
+        match b {
+            b'+' => {
+                let hex = [
+                    bytes.next().unwrap_or_default(),
+                    bytes.next().unwrap_or_default(),
+                ];
+                let code = std::str::from_utf8(&hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .filter(|code| (b' '..=b'~').contains(code))
+                    .ok_or_else(|| anyhow!("invalid xtext '{}'", value))?;
+                result.push(code);
+            }
+            b'!'..=b'~' if b != b'=' => result.push(b),
+            _ => return Err(anyhow!("invalid xtext '{}'", value)),
+        }
+    }
+
+    String::from_utf8(result).map_err(|_| anyhow!("invalid xtext '{}'", value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test1() {
+        let params = MailParams::parse(Some("hdrs"), Some("QQ314159+2Bx")).unwrap();
+        assert_eq!(params.ret, Some(Ret::Hdrs));
+        assert_eq!(params.envid.as_deref(), Some("QQ314159+x"));
+        assert!(MailParams::parse(Some("BODY"), None).is_err());
+        assert!(MailParams::parse(None, Some("a+4")).is_err());
+        assert!(MailParams::parse(None, Some("x+0D+0AX-Injected:+20yes")).is_err());
+        assert!(MailParams::parse(None, Some("x+7F")).is_err());
+        assert!(MailParams::parse(None, Some("+D0+9F")).is_err());
+
+        let params =
+            RcptParams::parse(Some("SUCCESS,FAILURE"), Some("rfc822;a+2Bb@example.com")).unwrap();
+        assert!(params.notify.success && params.notify.failure && !params.notify.delay);
+        assert_eq!(params.orcpt.as_deref(), Some("rfc822;a+b@example.com"));
+        let params = RcptParams::parse(Some("never"), None).unwrap();
+        assert!(!params.notify.failure);
+        assert_eq!(
+            RcptParams::parse(None, None).unwrap(),
+            RcptParams::default()
+        );
+        assert!(RcptParams::parse(Some("NEVER,SUCCESS"), None).is_err());
+        assert!(RcptParams::parse(None, Some("a@example.com")).is_err());
+        assert!(RcptParams::parse(None, Some("rfc822;a@example.com+0A")).is_err());
+    }
+
+    #[test]
+    fn report_test1() {
+        let statuses = vec![RecipientStatus {
+            recipient: "b@example.com".to_string(),
+            params: RcptParams::parse(None, Some("rfc822;b@example.com")).unwrap(),
+            action: Action::Failed,
+            status: "5.1.1",
+            diagnostic: "550 5.1.1 User unknown".to_string(),
+        }];
+        let params = MailParams::parse(None, Some("envid")).unwrap();
+        let message = b"Subject: test\r\n\r\nbody\r\n";
+        let report = report("a@example.com", Local::now(), &params, &statuses, message);
+        let report = String::from_utf8(report).unwrap();
+
+        assert!(report.contains("To: <a@example.com>\r\n"));
+        assert!(report.contains("Subject: Delivery Status Notification (Failure)\r\n"));
+        assert!(report.contains("Original-Envelope-Id: envid\r\n"));
+        assert!(report.contains(
+            "Original-Recipient: rfc822;b@example.com\r\n\
+             Final-Recipient: rfc822; b@example.com\r\n\
+             Action: failed\r\n\
+             Status: 5.1.1\r\n"
+        ));
+        assert!(report.contains("text/rfc822-headers\r\n\r\nSubject: test\r\n--"));
+        assert!(!report.contains("body"));
+        assert!(report.ends_with("--\r\n"));
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::hash_map::{self, HashMap};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Client address, sender and recipient of the delivery attempt.
+type Triplet = (IpAddr, String, String);
+
+#[derive(Debug)]
+struct Entry {
+    first_seen: Instant,
+    last_seen: Instant,
+    /// The triplet has been retried in time and is accepted until it is
+    /// not seen for `expire`.
+    passed: bool,
+}
+
+/// Table of delivery attempts shared by all sessions. The first attempt
+/// for every triplet is temporarily rejected, retries are accepted after
+/// `delay` has passed, but not later than `window` after that.
+#[derive(Debug)]
+pub struct Greylist {
+    delay: Duration,
+    window: Duration,
+    expire: Duration,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Debug)]
+struct Entries {
+    triplets: HashMap<Triplet, Entry>,
+    last_purge: Instant,
+}
+
+impl Greylist {
+    pub fn new(delay: Duration, window: Duration, expire: Duration) -> Self {
+        Greylist {
+            delay,
+            window,
+            expire,
+            entries: Mutex::new(Entries {
+                triplets: HashMap::new(),
+                last_purge: Instant::now(),
+            }),
+        }
+    }
+
+    /// Records delivery attempt and returns whether it's accepted.
+    pub fn check(&self, addr: IpAddr, from: &str, to: &str) -> bool {
+        self.check_at(addr, from, to, Instant::now())
+    }
+
+    fn check_at(&self, addr: IpAddr, from: &str, to: &str, now: Instant) -> bool {
+        let mut entries = self.entries.lock().unwrap();
+        let expiry = self.delay + self.window;
+
+        // Triplets which have never been retried in time or haven't been
+        // seen for long are dropped from time to time, so the table doesn't
+        // grow forever.
+        if now.saturating_duration_since(entries.last_purge) > expiry {
+            let expire = self.expire;
+            entries
+                .triplets
+                .retain(|_, entry| !entry.is_expired(now, expiry, expire));
+            entries.last_purge = now;
+        }
+
+        let triplet = (addr, from.to_lowercase(), to.to_lowercase());
+        let entry = match entries.triplets.entry(triplet) {
+            hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(Entry {
+                    first_seen: now,
+                    last_seen: now,
+                    passed: false,
+                });
+                return false;
+            }
+        };
+        if entry.is_expired(now, expiry, self.expire) {
+            // Retried too late, the attempt counts as the first one
+            *entry = Entry {
+                first_seen: now,
+                last_seen: now,
+                passed: false,
+            };
+            return false;
+        }
+
+        entry.last_seen = now;
+        if !entry.passed {
+            entry.passed = now.saturating_duration_since(entry.first_seen) >= self.delay;
+        }
+        entry.passed
+    }
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant, retry_expiry: Duration, expire: Duration) -> bool {
+        if self.passed {
+            now.saturating_duration_since(self.last_seen) > expire
+        } else {
+            now.saturating_duration_since(self.first_seen) > retry_expiry
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_test1() {
+        let greylist = Greylist::new(
+            Duration::from_secs(300),
+            Duration::from_secs(3600),
+            Duration::from_secs(86400),
+        );
+        let addr = "192.0.2.1".parse().unwrap();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert!(!greylist.check_at(addr, "a@example.com", "b@example.com", at(0)));
+        assert!(!greylist.check_at(addr, "a@example.com", "b@example.com", at(60)));
+        assert!(greylist.check_at(addr, "a@example.com", "B@example.com", at(301)));
+        assert!(greylist.check_at(addr, "a@example.com", "b@example.com", at(10000)));
+
+        // Other triplets are greylisted on their own
+        assert!(!greylist.check_at(addr, "a@example.com", "c@example.com", at(301)));
+        let other = "192.0.2.2".parse().unwrap();
+        assert!(!greylist.check_at(other, "a@example.com", "b@example.com", at(301)));
+
+        // Retry after the window starts over
+        assert!(!greylist.check_at(addr, "a@example.com", "c@example.com", at(5000)));
+        assert!(!greylist.check_at(addr, "a@example.com", "c@example.com", at(5100)));
+        assert!(greylist.check_at(addr, "a@example.com", "c@example.com", at(5301)));
+
+        // Passed triplet expires once it's not seen for a day
+        assert!(greylist.check_at(addr, "a@example.com", "b@example.com", at(90000)));
+        assert!(!greylist.check_at(addr, "a@example.com", "b@example.com", at(176401)));
+
+        // Expired triplets are purged
+        assert!(!greylist.check_at(other, "a@example.com", "c@example.com", at(300000)));
+        let entries = greylist.entries.lock().unwrap();
+        assert_eq!(entries.triplets.len(), 1);
+    }
+}
@@ -0,0 +1,685 @@
+use base64::Engine;
+use encoding_rs::{Encoding, UTF_8};
+
+/// Maximum multipart nesting that is split into parts.
+const MAX_DEPTH: usize = 32;
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ContentType {
+    pub mime_type: String,
+    pub params: Vec<(String, String)>,
+}
+
+impl ContentType {
+    pub fn parse(value: &str) -> Self {
+        let (mime_type, params) = parse_header_value(value);
+        ContentType {
+            mime_type: mime_type.to_lowercase(),
+            params,
+        }
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        find_param(&self.params, name)
+    }
+
+    pub fn is_multipart(&self) -> bool {
+        self.mime_type.starts_with("multipart/")
+    }
+}
+
+/// A single MIME entity. Multipart entities keep their children in `parts`,
+/// all other entities keep their body with the transfer encoding removed.
+/// Bodies of `text/*` entities are additionally converted to UTF-8 and kept
+/// in `text`. Header values have RFC 2047 encoded words decoded.
+#[derive(Debug, Default, Clone)]
+pub struct Part {
+    pub headers: Vec<(String, String)>,
+    pub content_type: ContentType,
+    pub body: Vec<u8>,
+    pub text: Option<String>,
+    pub parts: Vec<Part>,
+}
+
+impl Part {
+    pub fn parse(data: &[u8]) -> Self {
+        Self::parse_nested(data, 0)
+    }
+
+    /// Parses an entity nested `depth` multiparts deep. Multiparts nested
+    /// deeper than `MAX_DEPTH` are not split and keep their raw body, so a
+    /// hostile message cannot exhaust the stack here or in tree walkers.
+    fn parse_nested(data: &[u8], depth: usize) -> Self {
+        let (headers, body) = split_headers(data);
+        let headers = parse_headers(headers);
+
+        let content_type = find_header(&headers, "Content-Type")
+            .map(ContentType::parse)
+            .unwrap_or_else(|| ContentType::parse("text/plain; charset=us-ascii"));
+
+        let mut part = Part {
+            headers,
+            content_type,
+            ..Default::default()
+        };
+
+        let boundary = part
+            .content_type
+            .param("boundary")
+            .filter(|_| part.content_type.is_multipart() && depth < MAX_DEPTH)
+            .map(|boundary| boundary.to_string());
+
+        match boundary {
+            Some(boundary) => {
+                part.parts = split_multipart(body, &boundary)
+                    .into_iter()
+                    .map(|data| Part::parse_nested(data, depth + 1))
+                    .collect()
+            }
+            None => {
+                let encoding = part
+                    .header("Content-Transfer-Encoding")
+                    .unwrap_or_default()
+                    .trim()
+                    .to_lowercase();
+                part.body = decode_body(body, &encoding);
+                if part.content_type.mime_type.starts_with("text/") {
+                    let charset = part.content_type.param("charset").unwrap_or("us-ascii");
+                    part.text = Some(decode_charset(&part.body, charset));
+                }
+            }
+        }
+
+        part
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    pub fn filename(&self) -> Option<String> {
+        self.header("Content-Disposition")
+            .map(parse_header_value)
+            .and_then(|(_, params)| find_param(&params, "filename").map(str::to_string))
+            .or_else(|| self.content_type.param("name").map(str::to_string))
+    }
+
+    pub fn is_attachment(&self) -> bool {
+        let inline = self
+            .header("Content-Disposition")
+            .map(|value| parse_header_value(value).0.eq_ignore_ascii_case("inline"))
+            .unwrap_or(true);
+
+        !inline || self.filename().is_some()
+    }
+
+    pub fn subject(&self) -> Option<&str> {
+        self.header("Subject")
+    }
+
+    /// Returns the first inline `text/plain` body.
+    pub fn text_body(&self) -> Option<&str> {
+        self.find_text("text/plain")
+    }
+
+    /// Returns the first inline `text/html` body.
+    pub fn html_body(&self) -> Option<&str> {
+        self.find_text("text/html")
+    }
+
+    pub fn attachments(&self) -> Vec<&Part> {
+        self.leaves()
+            .into_iter()
+            .filter(|part| part.is_attachment())
+            .collect()
+    }
+
+    pub fn attachment(&self, filename: &str) -> Option<&Part> {
+        self.attachments()
+            .into_iter()
+            .find(|part| part.filename().as_deref() == Some(filename))
+    }
+
+    fn find_text(&self, mime_type: &str) -> Option<&str> {
+        self.leaves()
+            .into_iter()
+            .find(|part| part.content_type.mime_type == mime_type && !part.is_attachment())
+            .and_then(|part| part.text.as_deref())
+    }
+
+    /// Returns the number of bytes held by the entity and its children.
+    pub fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.len() + value.len())
+            .sum();
+        let parts: usize = self.parts.iter().map(Part::size).sum();
+        headers + self.body.len() + self.text.as_ref().map_or(0, String::len) + parts
+    }
+
+    /// Returns all non-multipart entities in depth-first order. Multiparts
+    /// left unsplit because of `MAX_DEPTH` are returned as opaque leaves.
+    pub fn leaves(&self) -> Vec<&Part> {
+        if self.parts.is_empty() {
+            if self.content_type.is_multipart() && self.body.is_empty() {
+                return Vec::new();
+            }
+            return vec![self];
+        }
+
+        self.parts.iter().flat_map(|part| part.leaves()).collect()
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn find_param<'a>(params: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+/// Parses only the header section of the message.
+pub fn headers(data: &[u8]) -> Vec<(String, String)> {
+    parse_headers(split_headers(data).0)
+}
+
+fn split_headers(data: &[u8]) -> (&[u8], &[u8]) {
+    let mut idx = 0;
+
+    for line in data.split_inclusive(|&b| b == b'\n') {
+        if line == b"\r\n" || line == b"\n" {
+            return (&data[..idx], &data[idx + line.len()..]);
+        }
+        idx += line.len();
+    }
+
+    (data, &[])
+}
+
+fn parse_headers(data: &[u8]) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in String::from_utf8_lossy(data).lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some((_, value)) = headers.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+            continue;
+        }
+        if let Some(idx) = line.find(':') {
+            headers.push((
+                line[..idx].trim().to_string(),
+                line[idx + 1..].trim().to_string(),
+            ));
+        }
+    }
+
+    for (_, value) in headers.iter_mut() {
+        if value.contains("=?") {
+            *value = decode_encoded_words(value);
+        }
+    }
+
+    headers
+}
+
+/// Decodes RFC 2047 encoded words, dropping whitespace between adjacent ones.
+fn decode_encoded_words(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    let mut rest = value;
+    let mut after_word = false;
+
+    while let Some(start) = rest.find("=?") {
+        let decoded = encoded_word_len(&rest[start + 2..])
+            .map(|len| &rest[start + 2..start + 2 + len])
+            .and_then(|word| decode_encoded_word(word).map(|text| (text, word.len() + 4)));
+
+        match decoded {
+            Some((text, len)) => {
+                let prefix = &rest[..start];
+                if !(after_word && prefix.trim().is_empty()) {
+                    result.push_str(prefix);
+                }
+                result.push_str(&text);
+                after_word = true;
+                rest = &rest[start + len..];
+            }
+            None => {
+                result.push_str(&rest[..start + 2]);
+                after_word = false;
+                rest = &rest[start + 2..];
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Returns length of `charset?encoding?text` following `=?`. The closing
+/// `?=` is looked for after the encoding, as Q-encoded text may start with
+/// `=`.
+fn encoded_word_len(word: &str) -> Option<usize> {
+    let charset = word.find('?')?;
+    let encoding = charset + 1 + word[charset + 1..].find('?')?;
+    let text = encoding + 1 + word[encoding + 1..].find("?=")?;
+    Some(text)
+}
+
+fn decode_encoded_word(word: &str) -> Option<String> {
+    let mut items = word.splitn(3, '?');
+    let charset = items.next()?;
+    let encoding = items.next()?;
+    let text = items.next()?;
+
+    // RFC 2231 allows a language suffix in the charset, e.g. `utf-8*en`.
+    let charset = charset.split('*').next().unwrap_or(charset);
+
+    let bytes = match encoding {
+        "B" | "b" => base64::engine::general_purpose::STANDARD
+            .decode(text)
+            .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(text))
+            .ok()?,
+        "Q" | "q" => decode_quoted_printable(text.replace('_', " ").as_bytes()),
+        _ => return None,
+    };
+
+    Some(decode_charset(&bytes, charset))
+}
+
+/// Converts text in the given charset to UTF-8. Unknown charsets are treated
+/// as UTF-8 with invalid sequences replaced.
+pub fn decode_charset(bytes: &[u8], charset: &str) -> String {
+    let encoding = match charset.trim().to_lowercase().as_str() {
+        "us-ascii" | "ascii" => UTF_8,
+        label => Encoding::for_label(label.as_bytes()).unwrap_or(UTF_8),
+    };
+    encoding.decode(bytes).0.into_owned()
+}
+
+/// Splits a structured header value like `text/plain; charset="utf-8"` into
+/// the leading value and the list of parameters.
+fn parse_header_value(value: &str) -> (String, Vec<(String, String)>) {
+    let mut items = split_unquoted(value, ';').into_iter();
+    let head = items.next().unwrap_or_default().trim().to_string();
+
+    let params = items
+        .filter_map(|item| {
+            let idx = item.find('=')?;
+            let name = item[..idx].trim().to_lowercase();
+            let value = item[idx + 1..].trim();
+            let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                value[1..value.len() - 1].replace("\\\"", "\"")
+            } else {
+                value.to_string()
+            };
+            Some((name, value))
+        })
+        .collect();
+
+    (head, combine_extended_params(params))
+}
+
+/// Number, "is percent-encoded" flag and value of a parameter section.
+type Section = (usize, bool, String);
+
+/// Joins RFC 2231 parameter continuations (`name*0`, `name*1*`, ...) and
+/// decodes extended values (`name*=charset'lang'%XX`).
+fn combine_extended_params(params: Vec<(String, String)>) -> Vec<(String, String)> {
+    let mut result: Vec<(String, String)> = Vec::new();
+    let mut extended: Vec<(String, Vec<Section>)> = Vec::new();
+
+    for (name, value) in params {
+        let (base, section, encoded) = match name.find('*') {
+            Some(idx) => {
+                let suffix = &name[idx + 1..];
+                let encoded = suffix.ends_with('*') || suffix.is_empty();
+                let section = suffix.trim_end_matches('*').parse::<usize>().unwrap_or(0);
+                (name[..idx].to_string(), section, encoded)
+            }
+            None => {
+                result.push((name, value));
+                continue;
+            }
+        };
+
+        match extended.iter_mut().find(|(name, _)| *name == base) {
+            Some((_, sections)) => sections.push((section, encoded, value)),
+            None => extended.push((base, vec![(section, encoded, value)])),
+        }
+    }
+
+    for (name, mut sections) in extended {
+        sections.sort_by_key(|(section, _, _)| *section);
+
+        let mut charset = "us-ascii".to_string();
+        let mut bytes = Vec::new();
+
+        for (idx, (_, encoded, value)) in sections.iter().enumerate() {
+            if !encoded {
+                bytes.extend_from_slice(value.as_bytes());
+                continue;
+            }
+
+            let mut value = value.as_str();
+            if idx == 0 {
+                let mut items = value.splitn(3, '\'');
+                if let (Some(cs), Some(_), Some(rest)) = (items.next(), items.next(), items.next())
+                {
+                    charset = cs.to_string();
+                    value = rest;
+                }
+            }
+            bytes.extend_from_slice(&percent_decode(value));
+        }
+
+        result.retain(|(key, _)| *key != name);
+        result.push((name, decode_charset(&bytes, &charset)));
+    }
+
+    result
+}
+
+pub fn percent_decode(value: &str) -> Vec<u8> {
+    let bytes = value.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+
+    while idx < bytes.len() {
+        let byte = bytes
+            .get(idx + 1..idx + 3)
+            .filter(|_| bytes[idx] == b'%')
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+
+        match byte {
+            Some(byte) => {
+                result.push(byte);
+                idx += 3;
+            }
+            None => {
+                result.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+
+    result
+}
+
+fn split_unquoted(value: &str, separator: char) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut quoted = false;
+    let mut escaped = false;
+    let mut start = 0;
+
+    for (idx, c) in value.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == separator && !quoted => {
+                items.push(&value[start..idx]);
+                start = idx + 1;
+            }
+            _ => {}
+        }
+    }
+    items.push(&value[start..]);
+
+    items
+}
+
+fn split_multipart<'a>(body: &'a [u8], boundary: &str) -> Vec<&'a [u8]> {
+    let delimiter = format!("--{}", boundary).into_bytes();
+    let mut parts = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut idx = 0;
+
+    for line in body.split_inclusive(|&b| b == b'\n') {
+        let trimmed = trim_line_end(line);
+
+        // Only whitespace or the closing "--" may follow a delimiter, so
+        // "--b1" does not match a line starting with "--b10".
+        let rest = trimmed.strip_prefix(delimiter.as_slice()).filter(|rest| {
+            rest.is_empty() || rest.starts_with(b"--") || rest[0] == b' ' || rest[0] == b'\t'
+        });
+
+        if let Some(rest) = rest {
+            if let Some(start) = start {
+                // The line break before the delimiter belongs to it.
+                let end = if body[..idx].ends_with(b"\r\n") {
+                    idx - 2
+                } else if body[..idx].ends_with(b"\n") {
+                    idx - 1
+                } else {
+                    idx
+                };
+                parts.push(&body[start..end.max(start)]);
+            }
+            if rest.starts_with(b"--") {
+                return parts;
+            }
+            start = Some(idx + line.len());
+        }
+
+        idx += line.len();
+    }
+
+    if let Some(start) = start {
+        parts.push(&body[start..]);
+    }
+
+    parts
+}
+
+fn trim_line_end(line: &[u8]) -> &[u8] {
+    let mut end = line.len();
+    while end > 0 && (line[end - 1] == b'\n' || line[end - 1] == b'\r') {
+        end -= 1;
+    }
+    &line[..end]
+}
+
+fn decode_body(body: &[u8], encoding: &str) -> Vec<u8> {
+    match encoding {
+        "base64" => {
+            let data: Vec<u8> = body
+                .iter()
+                .cloned()
+                .filter(|b| !b.is_ascii_whitespace())
+                .collect();
+            base64::engine::general_purpose::STANDARD
+                .decode(&data)
+                .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(&data))
+                .unwrap_or_else(|_| body.to_vec())
+        }
+        "quoted-printable" => decode_quoted_printable(body),
+        _ => body.to_vec(),
+    }
+}
+
+fn decode_quoted_printable(body: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(body.len());
+    let mut idx = 0;
+
+    while idx < body.len() {
+        let b = body[idx];
+        if b != b'=' {
+            result.push(b);
+            idx += 1;
+            continue;
+        }
+
+        let rest = &body[idx + 1..];
+        if rest.starts_with(b"\r\n") {
+            idx += 3;
+        } else if rest.starts_with(b"\n") {
+            idx += 2;
+        } else if let Some(byte) = rest
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            result.push(byte);
+            idx += 3;
+        } else {
+            result.push(b);
+            idx += 1;
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static MULTIPART: &[u8] = b"Subject: test\r\n\
+Content-Type: multipart/mixed;\r\n\
+\tboundary=\"outer\"\r\n\
+\r\n\
+preamble\r\n\
+--outer\r\n\
+Content-Type: multipart/alternative; boundary=inner\r\n\
+\r\n\
+--inner\r\n\
+Content-Type: text/plain; charset=utf-8\r\n\
+Content-Transfer-Encoding: quoted-printable\r\n\
+\r\n\
+caf=C3=A9 =\r\n\
+ok\r\n\
+--inner\r\n\
+Content-Type: text/html\r\n\
+\r\n\
+<p>hi</p>\r\n\
+--inner--\r\n\
+--outer\r\n\
+Content-Type: application/pdf; name=\"invoice.pdf\"\r\n\
+Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n\
+Content-Transfer-Encoding: base64\r\n\
+\r\n\
+JVBERi0x\r\n\
+LjQ=\r\n\
+--outer--\r\n";
+
+    #[test]
+    fn parse_test1() {
+        let part = Part::parse(b"Subject: hello\r\n world\r\n\r\nbody\r\n");
+        assert_eq!(part.header("subject"), Some("hello world"));
+        assert_eq!(part.content_type.mime_type, "text/plain");
+        assert_eq!(part.body, b"body\r\n");
+        assert_eq!(part.leaves().len(), 1);
+    }
+
+    #[test]
+    fn parse_test2() {
+        let part = Part::parse(MULTIPART);
+        assert_eq!(part.content_type.mime_type, "multipart/mixed");
+        assert_eq!(part.parts.len(), 2);
+
+        let leaves = part.leaves();
+        assert_eq!(leaves.len(), 3);
+        assert_eq!(leaves[0].body, "café ok".as_bytes());
+        assert_eq!(leaves[0].content_type.param("charset"), Some("utf-8"));
+        assert_eq!(leaves[1].body, b"<p>hi</p>");
+        assert!(!leaves[1].is_attachment());
+        assert_eq!(leaves[2].filename().as_deref(), Some("invoice.pdf"));
+        assert!(leaves[2].is_attachment());
+        assert_eq!(leaves[2].body, b"%PDF-1.4");
+    }
+
+    #[test]
+    fn parse_test3() {
+        let part = Part::parse(MULTIPART);
+        assert_eq!(part.subject(), Some("test"));
+        assert_eq!(part.text_body(), Some("café ok"));
+        assert_eq!(part.html_body(), Some("<p>hi</p>"));
+        assert_eq!(part.attachments().len(), 1);
+        assert!(part.attachment("invoice.pdf").is_some());
+        assert!(part.attachment("other.pdf").is_none());
+    }
+
+    #[test]
+    fn parse_test4() {
+        let part = Part::parse(
+            b"Subject: =?koi8-r?B?8NLJ18XU?= =?utf-8?Q?_=D0=BC=D0=B8=D1=80?=\r\n\
+Content-Type: text/plain; charset=windows-1251\r\n\
+Content-Disposition: attachment;\r\n \
+filename*0*=utf-8''%D1%84%D0%B0%D0%B9%D0%BB;\r\n \
+filename*1=\".txt\"\r\n\
+\r\n\
+\xcf\xf0\xe8\xe2\xe5\xf2",
+        );
+        assert_eq!(part.subject(), Some("Привет мир"));
+        assert_eq!(part.text.as_deref(), Some("Привет"));
+        assert_eq!(part.filename().as_deref(), Some("файл.txt"));
+    }
+
+    #[test]
+    fn parse_test5() {
+        let mut data = Vec::new();
+        for i in 0..10_000 {
+            let part = format!(
+                "Content-Type: multipart/mixed; boundary=b{0}\r\n\r\n--b{0}\r\n",
+                i
+            );
+            data.extend_from_slice(part.as_bytes());
+        }
+        let part = Part::parse(&data);
+
+        let mut depth = 0;
+        let mut node = &part;
+        while let Some(child) = node.parts.first() {
+            node = child;
+            depth += 1;
+        }
+        assert_eq!(depth, MAX_DEPTH);
+        assert!(node.content_type.is_multipart());
+        assert_eq!(part.leaves().len(), 1);
+    }
+
+    #[test]
+    fn split_multipart_test1() {
+        let parts = split_multipart(
+            b"--b1\r\none\r\n--b10\r\ntwo\r\n--b1 \r\nthree\r\n--b1--\r\n",
+            "b1",
+        );
+        assert_eq!(parts, vec![&b"one\r\n--b10\r\ntwo"[..], &b"three"[..]]);
+    }
+
+    #[test]
+    fn decode_encoded_words_test1() {
+        assert_eq!(decode_encoded_words("plain =?bad"), "plain =?bad");
+        assert_eq!(
+            decode_encoded_words("Re: =?UTF-8?B?0YLQtdGB0YI=?= done"),
+            "Re: тест done"
+        );
+        assert_eq!(
+            decode_encoded_words("=?us-ascii?Q?a?=  =?us-ascii?Q?b?="),
+            "ab"
+        );
+        assert_eq!(
+            decode_encoded_words("=?UTF-8?Q?=D0=9F=D1=80?= =?UTF-8?Q?=D0=B8?=!"),
+            "При!"
+        );
+    }
+
+    #[test]
+    fn content_type_test1() {
+        let ct = ContentType::parse("Text/HTML; charset=\"iso-8859-1\"; format=flowed");
+        assert_eq!(ct.mime_type, "text/html");
+        assert_eq!(ct.param("charset"), Some("iso-8859-1"));
+        assert_eq!(ct.param("FORMAT"), Some("flowed"));
+    }
+}
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Error};
+
+/// ESMTP parameters given after the path in MAIL and RCPT commands
+/// (RFC 5321, section 4.1.2).
+#[derive(Debug, Default)]
+pub struct Parameters<'a> {
+    params: Vec<(&'a str, Option<&'a str>)>,
+}
+
+impl<'a> Parameters<'a> {
+    /// Parses space separated `keyword[=value]` list. Keywords are case
+    /// insensitive and may not be repeated.
+    pub fn parse(input: &'a str) -> Result<Self, Error> {
+        let mut params: Vec<(&str, Option<&str>)> = Vec::new();
+
+        for param in input.split_whitespace() {
+            let (keyword, value) = match param.split_once('=') {
+                Some((keyword, value)) => (keyword, Some(value)),
+                None => (param, None),
+            };
+
+            if !is_keyword(keyword) {
+                return Err(anyhow!("invalid parameter keyword '{}'", keyword));
+            }
+            if let Some(value) = value {
+                if value.is_empty() || !value.chars().all(is_value_char) {
+                    return Err(anyhow!("invalid value of parameter '{}'", keyword));
+                }
+            }
+            if params
+                .iter()
+                .any(|(other, _)| other.eq_ignore_ascii_case(keyword))
+            {
+                return Err(anyhow!("duplicate parameter '{}'", keyword));
+            }
+
+            params.push((keyword, value));
+        }
+
+        Ok(Parameters { params })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &(&'a str, Option<&'a str>)> {
+        self.params.iter()
+    }
+
+    /// Returns value of the parameter, `None` if it is missing or has no
+    /// value.
+    pub fn value(&self, keyword: &str) -> Option<&'a str> {
+        self.params
+            .iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(keyword))
+            .and_then(|(_, value)| *value)
+    }
+}
+
+/// esmtp-keyword = (ALPHA / DIGIT) *(ALPHA / DIGIT / "-")
+fn is_keyword(keyword: &str) -> bool {
+    keyword.starts_with(|c: char| c.is_ascii_alphanumeric())
+        && keyword
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
+
+/// esmtp-value = 1*(%d33-60 / %d62-126), extended with non-ASCII
+/// characters by RFC 6531.
+fn is_value_char(c: char) -> bool {
+    (c.is_ascii_graphic() && c != '=') || (!c.is_ascii() && !c.is_control())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_test1() {
+        let params = Parameters::parse("SIZE=100 smtputf8 BODY=8BITMIME").unwrap();
+        assert_eq!(params.value("size"), Some("100"));
+        assert_eq!(params.value("SMTPUTF8"), None);
+        assert_eq!(params.value("BODY"), Some("8BITMIME"));
+        assert_eq!(params.iter().count(), 3);
+        assert_eq!(Parameters::parse("").unwrap().iter().count(), 0);
+
+        assert!(Parameters::parse("SIZE=").is_err());
+        assert!(Parameters::parse("SIZE=1 size=2").is_err());
+        assert!(Parameters::parse("-SIZE=1").is_err());
+        assert!(Parameters::parse("X_PARAM=1").is_err());
+        assert!(Parameters::parse("ENVID=a=b").is_err());
+    }
+}
@@ -0,0 +1,533 @@
+use anyhow::{anyhow, Error};
+use ipnet::IpNet;
+use rand::prelude::*;
+use std::fs;
+use std::net::IpAddr;
+
+use super::mime;
+use super::reply::Reply;
+
+/// Point of the session the rule is checked at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    Connect,
+    Helo,
+    Mail,
+    Rcpt,
+    Data,
+    /// End of the message content, before it is stored.
+    Eod,
+}
+
+impl Stage {
+    fn parse(name: &str) -> Result<Self, Error> {
+        match name.to_ascii_lowercase().as_str() {
+            "connect" => Ok(Stage::Connect),
+            "helo" => Ok(Stage::Helo),
+            "mail" => Ok(Stage::Mail),
+            "rcpt" => Ok(Stage::Rcpt),
+            "data" => Ok(Stage::Data),
+            "eod" => Ok(Stage::Eod),
+            _ => Err(anyhow!("unknown stage '{}'", name)),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    Peer(IpNet),
+    Helo(String),
+    From(String),
+    /// Matches if any of the recipients matches.
+    To(String),
+    SizeAbove(usize),
+    SizeBelow(usize),
+    /// Header name and the value pattern.
+    Header(String, String),
+}
+
+/// What the session looks like at the moment the rules are checked.
+#[derive(Debug, Default)]
+pub struct Context<'a> {
+    pub peer: Option<IpAddr>,
+    pub helo: &'a str,
+    pub from: &'a str,
+    /// The recipient being added at RCPT stage, all accepted ones later.
+    pub recipients: &'a [String],
+    /// Size declared with MAIL at MAIL, RCPT and DATA stages, the actual
+    /// size at the end of data.
+    pub size: Option<usize>,
+    pub message: Option<&'a [u8]>,
+}
+
+impl Condition {
+    fn matches(&self, context: &Context, headers: &mut Option<Vec<(String, String)>>) -> bool {
+        match self {
+            Condition::Peer(net) => context.peer.is_some_and(|peer| net.contains(&peer)),
+            Condition::Helo(pattern) => glob_match(pattern, context.helo),
+            Condition::From(pattern) => glob_match(pattern, context.from),
+            Condition::To(pattern) => context
+                .recipients
+                .iter()
+                .any(|recipient| glob_match(pattern, recipient)),
+            Condition::SizeAbove(limit) => context.size.is_some_and(|size| size > *limit),
+            Condition::SizeBelow(limit) => context.size.is_some_and(|size| size < *limit),
+            Condition::Header(name, pattern) => {
+                let message = match context.message {
+                    Some(message) => message,
+                    None => return false,
+                };
+                // Headers are parsed once for all rules checked
+                headers
+                    .get_or_insert_with(|| mime::headers(message))
+                    .iter()
+                    .filter(|(header, _)| header.eq_ignore_ascii_case(name))
+                    .any(|(_, value)| glob_match(pattern, value.trim()))
+            }
+        }
+    }
+}
+
+/// Way to break the connection instead of replying to the client.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Close the connection without the reply.
+    Close,
+    /// Abort the connection with TCP RST.
+    Reset,
+    /// Send the reply and stop reading from the client.
+    Stall,
+    /// Send the first half of the reply and close the connection.
+    Partial,
+    /// Reply with 421 and close the connection.
+    Shutdown,
+}
+
+impl Fault {
+    fn parse(name: &str) -> Result<Self, Error> {
+        match name.to_ascii_lowercase().as_str() {
+            "close" => Ok(Fault::Close),
+            "reset" => Ok(Fault::Reset),
+            "stall" => Ok(Fault::Stall),
+            "partial" => Ok(Fault::Partial),
+            "shutdown" => Ok(Fault::Shutdown),
+            _ => Err(anyhow!("unknown fault '{}'", name)),
+        }
+    }
+}
+
+/// What the rule does with the command.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// Reject the command with the reply.
+    Reject(Reply<'static>),
+    /// Process the command as usual, but break the connection.
+    Fault(Fault),
+}
+
+#[derive(Debug)]
+struct Rule {
+    stage: Stage,
+    conditions: Vec<Condition>,
+    probability: f32,
+    /// `None` to accept the command as usual.
+    action: Option<Action>,
+}
+
+/// Ordered list of rules deciding how to reply to the client. The first
+/// rule of the stage with all conditions met wins.
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Result<Self, Error> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses rules file with one rule per line:
+    ///
+    /// ```text
+    /// <stage> [<condition>...] [probability=<num>] => <reply>
+    /// ```
+    ///
+    /// Stage is one of `connect`, `helo`, `mail`, `rcpt`, `data` and `eod`
+    /// (end of data). Conditions are `peer=<addr or net>`, `helo=<glob>`,
+    /// `from=<glob>`, `to=<glob>`, `size>N`, `size<N` and
+    /// `header=<name>:<glob>`, where glob is a case-insensitive pattern with
+    /// `*` and `?` wildcards. Reply is either `accept` or `<code>
+    /// [<enhanced code>] <text>` with 4xx or 5xx code or `fault:<fault>`,
+    /// where fault is `close`, `reset`, `stall`, `partial` or `shutdown`.
+    /// Empty lines and lines starting with `#` are ignored.
+    pub fn parse(content: &str) -> Result<Self, Error> {
+        let mut policy = Policy::default();
+
+        for (idx, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let rule = Rule::parse(line)
+                .map_err(|err| anyhow!("policy file line {}: {}", idx + 1, err))?;
+            policy.rules.push(rule);
+        }
+
+        Ok(policy)
+    }
+
+    /// Adds rule rejecting recipients as unknown with the given
+    /// probability. It is checked after all other rules.
+    pub fn add_reject_ratio(&mut self, ratio: f32) {
+        self.rules.push(Rule {
+            stage: Stage::Rcpt,
+            conditions: Vec::new(),
+            probability: ratio,
+            action: Some(Action::Reject(Reply::unknown_user())),
+        });
+    }
+
+    /// Adds rule given on the command line as
+    /// `<stage>=<fault>[:<probability>]` which breaks the connection at the
+    /// stage. It is checked after the rules from the file.
+    pub fn add_fault(&mut self, spec: &str) -> Result<(), Error> {
+        let (stage, fault) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("fault must be given as <stage>=<fault>[:<probability>]"))?;
+        let (fault, probability) = match fault.split_once(':') {
+            Some((fault, probability)) => (fault, parse_probability(probability)?),
+            None => (fault, 1f32),
+        };
+
+        self.rules.push(Rule {
+            stage: Stage::parse(stage.trim())?,
+            conditions: Vec::new(),
+            probability,
+            action: Some(Action::Fault(Fault::parse(fault.trim())?)),
+        });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Returns what to do with the command at the stage instead of the
+    /// usual processing. Rules which have not fired because of their
+    /// probability are skipped, so the next matching rule may fire instead.
+    pub fn check(&self, stage: Stage, context: &Context) -> Option<Action> {
+        let mut headers = None;
+
+        self.rules
+            .iter()
+            .filter(|rule| rule.stage == stage)
+            .find(|rule| {
+                rule.conditions
+                    .iter()
+                    .all(|condition| condition.matches(context, &mut headers))
+                    && (rule.probability >= 1f32 || random::<f32>() < rule.probability)
+            })
+            .and_then(|rule| rule.action.clone())
+    }
+}
+
+impl Rule {
+    fn parse(line: &str) -> Result<Self, Error> {
+        let (conditions, reply) = line
+            .split_once("=>")
+            .ok_or_else(|| anyhow!("rule has no reply"))?;
+
+        let mut items = conditions.split_whitespace();
+        let stage = Stage::parse(items.next().unwrap_or_default())?;
+        let mut rule = Rule {
+            stage,
+            conditions: Vec::new(),
+            probability: 1f32,
+            action: parse_action(reply.trim())?,
+        };
+
+        for item in items {
+            let condition = if let Some(limit) = item.strip_prefix("size>") {
+                Condition::SizeAbove(parse_size(limit)?)
+            } else if let Some(limit) = item.strip_prefix("size<") {
+                Condition::SizeBelow(parse_size(limit)?)
+            } else {
+                let (name, value) = item
+                    .split_once('=')
+                    .ok_or_else(|| anyhow!("invalid condition '{}'", item))?;
+                match name {
+                    "peer" => Condition::Peer(
+                        value
+                            .parse::<IpNet>()
+                            .or_else(|_| value.parse::<IpAddr>().map(IpNet::from))
+                            .map_err(|_| anyhow!("invalid peer '{}'", value))?,
+                    ),
+                    "helo" => Condition::Helo(value.to_string()),
+                    "from" => Condition::From(value.to_string()),
+                    "to" => Condition::To(value.to_string()),
+                    "header" if stage == Stage::Eod => match value.split_once(':') {
+                        Some((name, pattern)) if !name.is_empty() => {
+                            Condition::Header(name.to_string(), pattern.to_string())
+                        }
+                        _ => return Err(anyhow!("invalid header condition '{}'", value)),
+                    },
+                    "header" => return Err(anyhow!("headers can only be checked at eod stage")),
+                    "probability" => {
+                        rule.probability = parse_probability(value)?;
+                        continue;
+                    }
+                    _ => return Err(anyhow!("unknown condition '{}'", name)),
+                }
+            };
+            rule.conditions.push(condition);
+        }
+
+        Ok(rule)
+    }
+}
+
+fn parse_size(value: &str) -> Result<usize, Error> {
+    value
+        .parse::<usize>()
+        .map_err(|_| anyhow!("invalid size '{}'", value))
+}
+
+fn parse_probability(value: &str) -> Result<f32, Error> {
+    match value.trim().parse::<f32>() {
+        Ok(probability) if (0f32..=1f32).contains(&probability) => Ok(probability),
+        _ => Err(anyhow!("probability must be between 0 and 1")),
+    }
+}
+
+/// Parses `accept`, `fault:<fault>` or `<code> [<enhanced code>] <text>`.
+/// Rules are loaded once and live as long as the process does, so the
+/// reply strings are leaked to be used in `Reply<'static>` like the
+/// built-in ones.
+fn parse_action(reply: &str) -> Result<Option<Action>, Error> {
+    if reply.eq_ignore_ascii_case("accept") {
+        return Ok(None);
+    }
+    if let Some(fault) = reply.strip_prefix("fault:") {
+        return Ok(Some(Action::Fault(Fault::parse(fault)?)));
+    }
+
+    let (code, text) = reply.split_once(' ').unwrap_or((reply, ""));
+    let status = match code.parse::<u16>() {
+        Ok(status) if (400..600).contains(&status) => status,
+        _ => return Err(anyhow!("reply code must be 4xx or 5xx, got '{}'", code)),
+    };
+
+    let text = text.trim_start();
+    let (enhanced_status, text) = match text.split_once(' ') {
+        Some((enhanced, text)) if is_enhanced_status(enhanced, status) => (Some(enhanced), text),
+        _ if is_enhanced_status(text, status) => (Some(text), ""),
+        _ => (None, text),
+    };
+    if text.trim().is_empty() {
+        return Err(anyhow!("reply text is missing"));
+    }
+
+    Ok(Some(Action::Reject(Reply {
+        status,
+        enhanced_status: enhanced_status.map(|code| &*Box::leak(code.into())),
+        lines: vec![Box::leak(text.trim().into())],
+    })))
+}
+
+/// Checks enhanced status code `class.subject.detail` (RFC 3463) has the
+/// same class as the reply code.
+fn is_enhanced_status(code: &str, status: u16) -> bool {
+    let parts: Vec<&str> = code.split('.').collect();
+    parts.len() == 3
+        && parts[0] == (status / 100).to_string()
+        && parts[1..]
+            .iter()
+            .all(|part| (1..=3).contains(&part.len()) && part.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Matches text against a case-insensitive pattern where `*` stands for
+/// any sequence of characters and `?` for any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, pos)) => {
+                    backtrack = Some((star, pos + 1));
+                    p = star + 1;
+                    t = pos + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reject(action: Option<Action>) -> Reply<'static> {
+        match action {
+            Some(Action::Reject(reply)) => reply,
+            action => panic!("unexpected action {:?}", action),
+        }
+    }
+
+    #[test]
+    fn glob_match_test1() {
+        assert!(glob_match("*@unknown.example", "User@Unknown.Example"));
+        assert!(glob_match("user?@*", "user1@example.com"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("*spam*", "Buy SPAM now"));
+        assert!(!glob_match("*@example.com", "user@example.org"));
+        assert!(!glob_match("user?@*", "user@example.com"));
+    }
+
+    #[test]
+    fn check_test1() {
+        let policy = Policy::parse(
+            "# test domains\n\
+             connect peer=192.0.2.0/24 => 421 4.7.0 Too many connections\n\
+             rcpt to=postmaster@* => accept\n\
+             rcpt to=*@unknown.example => 550 5.1.1 User unknown\n\
+             rcpt to=*@full.example probability=0 => 452 4.2.2 Mailbox full\n\
+             rcpt to=*@full.example => 452 Try later\n\
+             mail from=*@spammer.example size>1000 => 552 5.3.4 Too big\n\
+             eod header=subject:*spam* => 554 5.7.1 Looks like spam\n",
+        )
+        .unwrap();
+
+        let context = Context {
+            peer: Some("192.0.2.1".parse().unwrap()),
+            ..Default::default()
+        };
+        assert_eq!(reject(policy.check(Stage::Connect, &context)).status, 421);
+        assert!(policy.check(Stage::Helo, &context).is_none());
+
+        let rcpt = |recipient: &str| {
+            let recipients = [recipient.to_string()];
+            let context = Context {
+                recipients: &recipients,
+                ..Default::default()
+            };
+            policy.check(Stage::Rcpt, &context)
+        };
+        assert!(rcpt("postmaster@unknown.example").is_none());
+        let reply = reject(rcpt("user@unknown.example"));
+        assert_eq!(reply.to_string(), "550 5.1.1 User unknown\r\n");
+        let reply = reject(rcpt("user@full.example"));
+        assert_eq!(reply.to_string(), "452 Try later\r\n");
+        assert!(rcpt("user@example.com").is_none());
+
+        let context = Context {
+            from: "a@spammer.example",
+            size: Some(100),
+            ..Default::default()
+        };
+        assert!(policy.check(Stage::Mail, &context).is_none());
+        let context = Context {
+            from: "a@spammer.example",
+            size: Some(2000),
+            ..Default::default()
+        };
+        assert_eq!(reject(policy.check(Stage::Mail, &context)).status, 552);
+
+        let context = Context {
+            message: Some(b"Subject: Cheap SPAM\r\n\r\nbody\r\n"),
+            ..Default::default()
+        };
+        assert_eq!(reject(policy.check(Stage::Eod, &context)).status, 554);
+    }
+
+    #[test]
+    fn parse_test1() {
+        for rule in [
+            "rcpt to=*@example.com",
+            "rcpt => 250 Ok",
+            "rcpt => 550",
+            "rcpt => 550 5.1.1",
+            "quit => 550 Rejected",
+            "rcpt size=1 => 550 Rejected",
+            "rcpt peer=example.com => 550 Rejected",
+            "rcpt probability=2 => 550 Rejected",
+            "rcpt header=subject:* => 550 Rejected",
+            "rcpt => fault:crash",
+        ] {
+            assert!(Policy::parse(rule).is_err(), "{}", rule);
+        }
+
+        let policy = Policy::parse("rcpt => 550 4.1.1 Mismatched class").unwrap();
+        let reply = reject(policy.check(Stage::Rcpt, &Context::default()));
+        assert_eq!(reply.enhanced_status, None);
+        assert_eq!(reply.lines, vec!["4.1.1 Mismatched class"]);
+    }
+
+    #[test]
+    fn add_reject_ratio_test1() {
+        let mut policy = Policy::parse("rcpt to=postmaster@* => accept").unwrap();
+        policy.add_reject_ratio(1f32);
+        let recipients = ["postmaster@example.com".to_string()];
+        let context = Context {
+            recipients: &recipients,
+            ..Default::default()
+        };
+        assert!(policy.check(Stage::Rcpt, &context).is_none());
+        let reply = reject(policy.check(Stage::Rcpt, &Context::default()));
+        assert_eq!(reply.status, 550);
+
+        let mut policy = Policy::default();
+        policy.add_reject_ratio(0f32);
+        assert!(policy.check(Stage::Rcpt, &Context::default()).is_none());
+
+        // The ratio is the share of rejected recipients, not accepted ones.
+        let mut policy = Policy::default();
+        policy.add_reject_ratio(0.9);
+        let rejected = (0..1000)
+            .filter(|_| policy.check(Stage::Rcpt, &Context::default()).is_some())
+            .count();
+        assert!(rejected > 800, "{}", rejected);
+    }
+
+    #[test]
+    fn add_fault_test1() {
+        let mut policy = Policy::parse("rcpt to=*@reset.example => fault:reset").unwrap();
+        policy.add_fault("data=stall").unwrap();
+        policy.add_fault("rcpt=close:0").unwrap();
+
+        let recipients = ["a@reset.example".to_string()];
+        let context = Context {
+            recipients: &recipients,
+            ..Default::default()
+        };
+        assert!(matches!(
+            policy.check(Stage::Rcpt, &context),
+            Some(Action::Fault(Fault::Reset))
+        ));
+        assert!(matches!(
+            policy.check(Stage::Data, &context),
+            Some(Action::Fault(Fault::Stall))
+        ));
+        assert!(policy.check(Stage::Rcpt, &Context::default()).is_none());
+
+        for spec in ["rcpt", "rcpt=explode", "quit=close", "rcpt=close:2"] {
+            assert!(policy.add_fault(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -7,28 +7,49 @@ use std::fmt;
 // }
 
 static OK_STATUS_CODE: u16 = 250;
+static CANNOT_VERIFY_STATUS_CODE: u16 = 252;
+static HELP_STATUS_CODE: u16 = 214;
+static READY_STATUS_CODE: u16 = 220;
+static AUTH_SUCCESS_STATUS_CODE: u16 = 235;
+static AUTH_CHALLENGE_STATUS_CODE: u16 = 334;
 static BYE_STATUS_CODE: u16 = 221;
 static DATA_STATUS_CODE: u16 = 354;
 static UNKNOWN_COMMAND_STATUS_CODE: u16 = 500;
-static INVALID_ADDRESS_STATUS_CODE: u16 = 502;
+static SYNTAX_ERROR_STATUS_CODE: u16 = 501;
+static NOT_IMPLEMENTED_STATUS_CODE: u16 = 502;
+static BAD_SEQUENCE_STATUS_CODE: u16 = 503;
+static UNSUPPORTED_MECHANISM_STATUS_CODE: u16 = 504;
+static AUTH_FAILED_STATUS_CODE: u16 = 535;
+static INVALID_ADDRESS_STATUS_CODE: u16 = 501;
 static MESSAGE_TOO_BIG_STATUS_CODE: u16 = 556;
 static UNKNOWN_USER_STATUS_CODE: u16 = 550;
+static MAILBOX_NOT_ALLOWED_STATUS_CODE: u16 = 553;
+static UNKNOWN_PARAMETER_STATUS_CODE: u16 = 555;
 static TOO_MANY_RECIPIENTS_STATUS_CODE: u16 = 452;
+static MAILBOX_FULL_STATUS_CODE: u16 = 552;
+static LOCAL_ERROR_STATUS_CODE: u16 = 451;
+static SERVICE_UNAVAILABLE_STATUS_CODE: u16 = 421;
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct Reply<'a> {
     pub status: u16,
+    /// Enhanced status code (RFC 3463) prepended to every line of the reply.
+    pub enhanced_status: Option<&'static str>,
     pub lines: Vec<&'a str>,
 }
 
 impl<'a> fmt::Display for Reply<'a> {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         let len = self.lines.len();
-        if len > 0 {
-            for idx in 0..len - 1 {
-                fmt.write_fmt(format_args!("{}-{}\r\n", self.status, self.lines[idx]))?;
+        for (idx, line) in self.lines.iter().enumerate() {
+            let separator = if idx + 1 < len { '-' } else { ' ' };
+            match self.enhanced_status {
+                Some(code) => fmt.write_fmt(format_args!(
+                    "{}{}{} {}\r\n",
+                    self.status, separator, code, line
+                ))?,
+                None => fmt.write_fmt(format_args!("{}{}{}\r\n", self.status, separator, line))?,
             }
-            fmt.write_fmt(format_args!("{} {}\r\n", self.status, self.lines[len - 1]))?;
         }
         Ok(())
     }
@@ -39,6 +60,7 @@ impl<'a> Reply<'a> {
         Reply {
             status: OK_STATUS_CODE,
             lines: vec![message],
+            enhanced_status: Some("2.0.0"),
         }
     }
 
@@ -46,6 +68,51 @@ impl<'a> Reply<'a> {
         Reply {
             status: OK_STATUS_CODE,
             lines: messages,
+            enhanced_status: None,
+        }
+    }
+
+    /// Positive reply to the command which has its own enhanced status code
+    /// (e.g. `2.1.5` for RCPT).
+    pub fn ok_with(enhanced_status: &'static str, message: &'a str) -> Self {
+        Reply {
+            status: OK_STATUS_CODE,
+            enhanced_status: Some(enhanced_status),
+            lines: vec![message],
+        }
+    }
+
+    /// Reply to HELO which must not have enhanced status code.
+    pub fn hello(message: &'a str) -> Self {
+        Reply {
+            status: OK_STATUS_CODE,
+            enhanced_status: None,
+            lines: vec![message],
+        }
+    }
+
+    /// Reply to VRFY or EXPN with one mailbox per line.
+    pub fn mailboxes(mailboxes: Vec<&'a str>) -> Self {
+        Reply {
+            status: OK_STATUS_CODE,
+            enhanced_status: Some("2.1.5"),
+            lines: mailboxes,
+        }
+    }
+
+    pub fn cannot_verify() -> Self {
+        Reply {
+            status: CANNOT_VERIFY_STATUS_CODE,
+            enhanced_status: Some("2.1.5"),
+            lines: vec!["Cannot VRFY user, but will accept message and attempt delivery"],
+        }
+    }
+
+    pub fn help(commands: &'a str) -> Self {
+        Reply {
+            status: HELP_STATUS_CODE,
+            enhanced_status: Some("2.0.0"),
+            lines: vec!["Supported commands:", commands, "End of HELP info"],
         }
     }
 
@@ -53,6 +120,7 @@ impl<'a> Reply<'a> {
         Reply {
             status: BYE_STATUS_CODE,
             lines: vec!["Bye"],
+            enhanced_status: Some("2.0.0"),
         }
     }
 
@@ -60,6 +128,95 @@ impl<'a> Reply<'a> {
         Reply {
             status: DATA_STATUS_CODE,
             lines: vec!["End data with <CR><LF>.<CR><LF>"],
+            enhanced_status: None,
+        }
+    }
+
+    pub fn ready_to_start_tls() -> Self {
+        Reply {
+            status: READY_STATUS_CODE,
+            lines: vec!["Ready to start TLS"],
+            enhanced_status: Some("2.0.0"),
+        }
+    }
+
+    pub fn syntax_error() -> Self {
+        Reply {
+            status: SYNTAX_ERROR_STATUS_CODE,
+            lines: vec!["Syntax error in parameters or arguments"],
+            enhanced_status: Some("5.5.2"),
+        }
+    }
+
+    pub fn invalid_parameter() -> Self {
+        Reply {
+            status: SYNTAX_ERROR_STATUS_CODE,
+            enhanced_status: Some("5.5.4"),
+            lines: vec!["Invalid command parameter"],
+        }
+    }
+
+    pub fn unknown_parameter() -> Self {
+        Reply {
+            status: UNKNOWN_PARAMETER_STATUS_CODE,
+            enhanced_status: Some("5.5.4"),
+            lines: vec!["MAIL FROM/RCPT TO parameters not recognized or not implemented"],
+        }
+    }
+
+    pub fn not_implemented() -> Self {
+        Reply {
+            status: NOT_IMPLEMENTED_STATUS_CODE,
+            enhanced_status: Some("5.5.1"),
+            lines: vec!["Command not implemented"],
+        }
+    }
+
+    pub fn bad_sequence() -> Self {
+        Reply {
+            status: BAD_SEQUENCE_STATUS_CODE,
+            lines: vec!["Bad sequence of commands"],
+            enhanced_status: Some("5.5.1"),
+        }
+    }
+
+    pub fn auth_challenge(challenge: &'a str) -> Self {
+        Reply {
+            status: AUTH_CHALLENGE_STATUS_CODE,
+            lines: vec![challenge],
+            enhanced_status: None,
+        }
+    }
+
+    pub fn auth_success() -> Self {
+        Reply {
+            status: AUTH_SUCCESS_STATUS_CODE,
+            lines: vec!["Authentication successful"],
+            enhanced_status: Some("2.7.0"),
+        }
+    }
+
+    pub fn auth_failed() -> Self {
+        Reply {
+            status: AUTH_FAILED_STATUS_CODE,
+            lines: vec!["Authentication credentials invalid"],
+            enhanced_status: Some("5.7.8"),
+        }
+    }
+
+    pub fn auth_cancelled() -> Self {
+        Reply {
+            status: SYNTAX_ERROR_STATUS_CODE,
+            lines: vec!["Authentication cancelled"],
+            enhanced_status: Some("5.0.0"),
+        }
+    }
+
+    pub fn unsupported_mechanism() -> Self {
+        Reply {
+            status: UNSUPPORTED_MECHANISM_STATUS_CODE,
+            lines: vec!["Unrecognized authentication type"],
+            enhanced_status: Some("5.5.4"),
         }
     }
 
@@ -67,6 +224,7 @@ impl<'a> Reply<'a> {
         Reply {
             status: UNKNOWN_COMMAND_STATUS_CODE,
             lines: vec!["Invalid or out of order command"],
+            enhanced_status: Some("5.5.1"),
         }
     }
 
@@ -74,6 +232,7 @@ impl<'a> Reply<'a> {
         Reply {
             status: INVALID_ADDRESS_STATUS_CODE,
             lines: vec!["Malformed email address"],
+            enhanced_status: Some("5.1.3"),
         }
     }
 
@@ -81,6 +240,15 @@ impl<'a> Reply<'a> {
         Reply {
             status: MESSAGE_TOO_BIG_STATUS_CODE,
             lines: vec!["Message size exceeds maximum allowed"],
+            enhanced_status: Some("5.3.4"),
+        }
+    }
+
+    pub fn non_ascii_address() -> Self {
+        Reply {
+            status: MAILBOX_NOT_ALLOWED_STATUS_CODE,
+            lines: vec!["Non-ASCII addresses require SMTPUTF8"],
+            enhanced_status: Some("5.6.7"),
         }
     }
 
@@ -88,6 +256,23 @@ impl<'a> Reply<'a> {
         Reply {
             status: UNKNOWN_USER_STATUS_CODE,
             lines: vec!["User unknown"],
+            enhanced_status: Some("5.1.1"),
+        }
+    }
+
+    pub fn unknown_list() -> Self {
+        Reply {
+            status: UNKNOWN_USER_STATUS_CODE,
+            enhanced_status: Some("5.1.1"),
+            lines: vec!["Mailing list unknown"],
+        }
+    }
+
+    pub fn mailbox_full() -> Self {
+        Reply {
+            status: MAILBOX_FULL_STATUS_CODE,
+            enhanced_status: Some("5.2.2"),
+            lines: vec!["Mailbox full"],
         }
     }
 
@@ -95,6 +280,57 @@ impl<'a> Reply<'a> {
         Reply {
             status: TOO_MANY_RECIPIENTS_STATUS_CODE,
             lines: vec!["Too many recipients"],
+            enhanced_status: Some("4.5.3"),
+        }
+    }
+
+    pub fn greylisted() -> Self {
+        Reply {
+            status: LOCAL_ERROR_STATUS_CODE,
+            lines: vec!["Greylisted, please try again later"],
+            enhanced_status: Some("4.7.1"),
         }
     }
+
+    pub fn service_unavailable() -> Self {
+        Reply {
+            status: SERVICE_UNAVAILABLE_STATUS_CODE,
+            lines: vec!["Service not available, closing transmission channel"],
+            enhanced_status: Some("4.3.2"),
+        }
+    }
+
+    pub fn local_error() -> Self {
+        Reply {
+            status: LOCAL_ERROR_STATUS_CODE,
+            lines: vec!["Requested action aborted: local error in processing"],
+            enhanced_status: Some("4.3.0"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_test1() {
+        assert_eq!(
+            Reply::unknown_user().to_string(),
+            "550 5.1.1 User unknown\r\n"
+        );
+        assert_eq!(
+            Reply::ok_many(vec!["first", "second"]).to_string(),
+            "250-first\r\n250 second\r\n"
+        );
+        let reply = Reply {
+            status: 451,
+            enhanced_status: Some("4.7.1"),
+            lines: vec!["Try", "again later"],
+        };
+        assert_eq!(
+            reply.to_string(),
+            "451-4.7.1 Try\r\n451 4.7.1 again later\r\n"
+        );
+    }
 }
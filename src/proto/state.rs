@@ -6,5 +6,11 @@ pub enum State {
     Mail,
     Rcpt,
     Data,
+    /// Waiting for the chunk announced by BDAT.
+    Chunk,
+    /// Between chunks of the message sent with BDAT.
+    Chunking,
+    StartTls,
+    Auth,
     Done,
 }
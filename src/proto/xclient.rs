@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Error};
+use serde::Serialize;
+use std::net::IpAddr;
+
+use super::address::validate_host;
+use super::dsn::decode_xtext;
+
+/// EHLO keyword with the attributes accepted by XCLIENT.
+pub static XCLIENT_ATTRIBUTES: &str = "XCLIENT NAME ADDR PORT PROTO HELO LOGIN";
+/// EHLO keyword with the attributes accepted by XFORWARD.
+pub static XFORWARD_ATTRIBUTES: &str = "XFORWARD NAME ADDR PORT PROTO HELO IDENT SOURCE";
+
+/// Attributes of the original client passed by a trusted proxy or content
+/// filter with XCLIENT and XFORWARD commands (Postfix extensions).
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct Attributes {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub addr: Option<IpAddr>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub proto: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub helo: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub login: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ident: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+}
+
+impl Attributes {
+    /// Updates attributes from `NAME=value ...` command arguments. Names
+    /// must be listed in `keyword` (one of the EHLO keywords above), values
+    /// are xtext encoded and may not contain control characters, since they
+    /// end up in the trace headers. NAME and HELO must be host names or
+    /// address literals, PROTO is SMTP or ESMTP. `[UNAVAILABLE]` and
+    /// `[TEMPUNAVAIL]` values reset the attribute. Attributes are left
+    /// untouched on error.
+    pub fn update(&mut self, args: &str, keyword: &str) -> Result<(), Error> {
+        let supported: Vec<&str> = keyword.split(' ').skip(1).collect();
+        let mut attributes = self.clone();
+
+        if args.is_empty() {
+            return Err(anyhow!("no attributes given"));
+        }
+
+        for item in args.split_whitespace() {
+            let (name, value) = item
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid attribute '{}'", item))?;
+            let name = name.to_ascii_uppercase();
+            if !supported.contains(&name.as_str()) {
+                return Err(anyhow!("unsupported attribute '{}'", name));
+            }
+
+            let value = decode_xtext(value)?;
+            let value = match value.as_str() {
+                "[UNAVAILABLE]" | "[TEMPUNAVAIL]" => None,
+                _ => Some(value),
+            };
+
+            match name.as_str() {
+                "NAME" => attributes.name = value.map(parse_host).transpose()?,
+                "ADDR" => attributes.addr = value.as_deref().map(parse_addr).transpose()?,
+                "PORT" => {
+                    attributes.port = value
+                        .as_deref()
+                        .map(|port| {
+                            port.parse::<u16>()
+                                .map_err(|_| anyhow!("invalid PORT value '{}'", port))
+                        })
+                        .transpose()?
+                }
+                "PROTO" => attributes.proto = value.map(parse_proto).transpose()?,
+                "HELO" => attributes.helo = value.map(parse_host).transpose()?,
+                "LOGIN" => attributes.login = value,
+                "IDENT" => attributes.ident = value,
+                "SOURCE" => attributes.source = value,
+                _ => unreachable!(),
+            }
+        }
+
+        *self = attributes;
+        Ok(())
+    }
+}
+
+/// Checks that NAME or HELO value is a host name or an address literal.
+fn parse_host(host: String) -> Result<String, Error> {
+    match validate_host(&host) {
+        Ok(()) => Ok(host),
+        Err(_) => Err(anyhow!("invalid host name '{}'", host)),
+    }
+}
+
+fn parse_proto(proto: String) -> Result<String, Error> {
+    if proto.eq_ignore_ascii_case("SMTP") || proto.eq_ignore_ascii_case("ESMTP") {
+        Ok(proto)
+    } else {
+        Err(anyhow!("invalid PROTO value '{}'", proto))
+    }
+}
+
+/// Parses IPv4 address or IPv6 address prefixed with `IPV6:`.
+fn parse_addr(addr: &str) -> Result<IpAddr, Error> {
+    let ip = match addr.get(..5) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("IPV6:") => addr[5..].parse::<IpAddr>(),
+        _ => addr.parse::<IpAddr>(),
+    };
+    ip.map_err(|_| anyhow!("invalid ADDR value '{}'", addr))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn update_test1() {
+        let mut attributes = Attributes::default();
+        attributes
+            .update(
+                "NAME=mail.example.com ADDR=192.0.2.1 port=25 HELO=[192.0.2.1] PROTO=ESMTP",
+                XCLIENT_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(attributes.name.as_deref(), Some("mail.example.com"));
+        assert_eq!(attributes.addr, Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(attributes.port, Some(25));
+        assert_eq!(attributes.helo.as_deref(), Some("[192.0.2.1]"));
+        assert_eq!(attributes.proto.as_deref(), Some("ESMTP"));
+
+        attributes
+            .update(
+                "NAME=[UNAVAILABLE] ADDR=IPV6:2001:db8::1 LOGIN=a+2Bb",
+                XCLIENT_ATTRIBUTES,
+            )
+            .unwrap();
+        assert_eq!(attributes.name, None);
+        assert_eq!(attributes.addr, Some("2001:db8::1".parse().unwrap()));
+        assert_eq!(attributes.login.as_deref(), Some("a+b"));
+        assert_eq!(attributes.port, Some(25));
+    }
+
+    #[test]
+    fn update_test2() {
+        let mut attributes = Attributes::default();
+        for args in [
+            "",
+            "NAME",
+            "IDENT=id",
+            "ADDR=example.com",
+            "PORT=65536",
+            "NAME=a HELO=b+",
+            "NAME=a+0D+0AX-Evil:+20x",
+            "NAME=mail+20example",
+            "HELO=[192.0.2.1",
+            "HELO=-client",
+            "PROTO=HTTP",
+            "LOGIN=a+09b",
+        ] {
+            assert!(
+                attributes.update(args, XCLIENT_ATTRIBUTES).is_err(),
+                "{}",
+                args
+            );
+        }
+        assert_eq!(attributes, Attributes::default());
+
+        attributes
+            .update("IDENT=id SOURCE=REMOTE", XFORWARD_ATTRIBUTES)
+            .unwrap();
+        assert_eq!(attributes.source.as_deref(), Some("REMOTE"));
+        assert!(attributes.update("LOGIN=a", XFORWARD_ATTRIBUTES).is_err());
+        assert!(attributes
+            .update("PROTO=LMTP", XFORWARD_ATTRIBUTES)
+            .is_err());
+        attributes
+            .update("PROTO=smtp HELO=[IPv6:2001:db8::1]", XFORWARD_ATTRIBUTES)
+            .unwrap();
+        assert_eq!(attributes.proto.as_deref(), Some("smtp"));
+    }
+}
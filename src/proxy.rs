@@ -0,0 +1,176 @@
+use anyhow::{anyhow, Error};
+use std::io::Read;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+/// Signature every PROXY protocol v2 header starts with.
+static V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Maximum length of PROXY protocol v1 header including CRLF.
+static V1_MAX_LENGTH: usize = 107;
+
+/// Reads HAProxy PROXY protocol header (version 1 or 2) sent by the proxy
+/// before any client data and returns the original client address. `None`
+/// is returned for connections the proxy has made on its own (`LOCAL`
+/// command, `UNKNOWN` protocol), which keep the address of the proxy.
+///
+/// The header is read without buffering, so the stream is positioned right
+/// after it and can be used for TLS handshake.
+pub fn read_header<R: Read>(stream: &mut R) -> Result<Option<SocketAddr>, Error> {
+    let mut header = [0u8; 12];
+    stream.read_exact(&mut header)?;
+
+    if &header == V2_SIGNATURE {
+        read_v2(stream)
+    } else if header.starts_with(b"PROXY ") {
+        let mut line = header.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= V1_MAX_LENGTH {
+                return Err(anyhow!("PROXY header is too long"));
+            }
+            let mut byte = [0u8; 1];
+            stream.read_exact(&mut byte)?;
+            line.push(byte[0]);
+        }
+        parse_v1(&line)
+    } else {
+        Err(anyhow!("PROXY protocol header is missing"))
+    }
+}
+
+/// Parses `PROXY TCP4|TCP6|UNKNOWN <src> <dst> <sport> <dport>\r\n`.
+fn parse_v1(line: &[u8]) -> Result<Option<SocketAddr>, Error> {
+    let line = std::str::from_utf8(line)
+        .map_err(|_| anyhow!("invalid PROXY header"))?
+        .trim_end_matches("\r\n");
+    let items: Vec<&str> = line.split(' ').collect();
+
+    match items.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, _dst, sport, _dport] => {
+            let ip = src
+                .parse::<IpAddr>()
+                .map_err(|_| anyhow!("invalid PROXY source address '{}'", src))?;
+            if ip.is_ipv4() != (*family == "TCP4") {
+                return Err(anyhow!("PROXY source address '{}' is not {}", src, family));
+            }
+            let port = sport
+                .parse::<u16>()
+                .map_err(|_| anyhow!("invalid PROXY source port '{}'", sport))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("invalid PROXY header '{}'", line)),
+    }
+}
+
+/// Reads the rest of the binary header following the signature.
+fn read_v2<R: Read>(stream: &mut R) -> Result<Option<SocketAddr>, Error> {
+    let mut header = [0u8; 4];
+    stream.read_exact(&mut header)?;
+
+    let version = header[0] >> 4;
+    let command = header[0] & 0x0f;
+    let family = header[1];
+    let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    if version != 2 {
+        return Err(anyhow!("unsupported PROXY protocol version {}", version));
+    }
+
+    // Addresses are followed by optional TLVs which are not used
+    let mut data = vec![0u8; len];
+    stream.read_exact(&mut data)?;
+
+    match command {
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(anyhow!("unknown PROXY command {}", command)),
+    }
+
+    // High nibble is the address family, low nibble is the transport
+    match family >> 4 {
+        0x1 if data.len() >= 12 => {
+            let ip = Ipv4Addr::new(data[0], data[1], data[2], data[3]);
+            let port = u16::from_be_bytes([data[8], data[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        0x2 if data.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[..16]);
+            let port = u16::from_be_bytes([data[32], data[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        0x1 | 0x2 => Err(anyhow!("PROXY header is truncated")),
+        // AF_UNSPEC and AF_UNIX
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_header_test1() {
+        let mut input: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 25\r\nEHLO";
+        assert_eq!(
+            read_header(&mut input).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(input, b"EHLO");
+
+        let mut input: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 4000 25\r\n";
+        assert_eq!(
+            read_header(&mut input).unwrap(),
+            Some("[2001:db8::1]:4000".parse().unwrap())
+        );
+
+        let mut input: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut input).unwrap(), None);
+
+        for input in [
+            &b"EHLO client\r\nQUIT\r\n"[..],
+            b"PROXY TCP4 2001:db8::1 192.0.2.1 1 25\r\n",
+            b"PROXY TCP4 192.0.2.1 192.0.2.2 70000 25\r\n",
+            b"PROXY TCP4 192.0.2.1\r\n",
+        ] {
+            let mut input = input;
+            assert!(read_header(&mut input).is_err());
+        }
+
+        let mut input = b"PROXY TCP4 ".to_vec();
+        input.resize(200, b'1');
+        assert!(read_header(&mut input.as_slice()).is_err());
+    }
+
+    #[test]
+    fn read_header_test2() {
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 15]);
+        input.extend_from_slice(&[192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0, 25]);
+        input.extend_from_slice(&[0x04, 0, 0]);
+        input.extend_from_slice(b"EHLO");
+        let mut stream = input.as_slice();
+        assert_eq!(
+            read_header(&mut stream).unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(stream, b"EHLO");
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x21, 0, 36]);
+        input.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        input.extend_from_slice(&Ipv6Addr::LOCALHOST.octets());
+        input.extend_from_slice(&[0x0f, 0xa0, 0, 25]);
+        assert_eq!(
+            read_header(&mut input.as_slice()).unwrap(),
+            Some("[::1]:4000".parse().unwrap())
+        );
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut input.as_slice()).unwrap(), None);
+
+        let mut input = V2_SIGNATURE.to_vec();
+        input.extend_from_slice(&[0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(read_header(&mut input.as_slice()).is_err());
+    }
+}
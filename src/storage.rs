@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Error};
+use chrono::{DateTime, Local};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+pub mod eml;
+pub mod maildir;
+pub mod mbox;
+pub mod memory;
+
+use self::eml::EmlDir;
+use self::maildir::Maildir;
+use self::mbox::Mbox;
+
+use crate::proto::xclient::Attributes;
+use crate::proto::HOSTNAME;
+
+/// Destination for messages accepted at the end of the DATA phase.
+pub trait MessageSink: fmt::Debug + Send + Sync {
+    fn store(&self, envelope: &Envelope, message: &[u8]) -> Result<(), Error>;
+}
+
+/// Creates a sink from the `<format>:<path>` specification given on the
+/// command line.
+pub fn open_sink(spec: &str) -> Result<Arc<dyn MessageSink>, Error> {
+    let (format, path) = match spec.find(':') {
+        Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+        None => return Err(anyhow!("storage must be given as <format>:<path>")),
+    };
+
+    if path.is_empty() {
+        return Err(anyhow!("storage path can't be empty"));
+    }
+
+    let sink: Arc<dyn MessageSink> = match format {
+        "maildir" => Arc::new(Maildir::open(path)?),
+        "mbox" => Arc::new(Mbox::open(path)?),
+        "eml" => Arc::new(EmlDir::open(path)?),
+        _ => return Err(anyhow!("unknown storage format '{}'", format)),
+    };
+
+    Ok(sink)
+}
+
+#[derive(Debug, Clone)]
+pub struct Envelope {
+    pub peer_addr: SocketAddr,
+    pub from: String,
+    pub recipients: Vec<String>,
+    pub received_at: DateTime<Local>,
+    /// Protocol name for the `Received` header as registered in RFC 3848.
+    pub protocol: &'static str,
+    /// User name the client has authenticated as with AUTH command.
+    pub auth: Option<String>,
+    /// Client attributes overridden with XCLIENT command.
+    pub xclient: Option<Attributes>,
+    /// Original client attributes passed with XFORWARD command.
+    pub xforward: Option<Attributes>,
+    /// Message was sent with `BODY=BINARYMIME`, so CRLF pairs in it are not
+    /// necessarily line endings.
+    pub binary: bool,
+}
+
+impl Envelope {
+    pub fn new(peer_addr: SocketAddr, from: &str, recipients: &[String]) -> Self {
+        Envelope {
+            peer_addr,
+            from: from.to_string(),
+            recipients: recipients.to_vec(),
+            received_at: Local::now(),
+            protocol: "ESMTP",
+            auth: None,
+            xclient: None,
+            xforward: None,
+            binary: false,
+        }
+    }
+
+    /// Builds `Return-Path` and `Received` headers which are prepended to the
+    /// stored message the same way a real MTA does on final delivery.
+    pub fn trace_headers(&self) -> String {
+        let mut headers = format!("Return-Path: <{}>\r\n", self.from);
+
+        let name = match self.xclient {
+            Some(Attributes {
+                name: Some(ref name),
+                ..
+            }) => name.clone(),
+            _ => format!("[{}]", self.peer_addr.ip()),
+        };
+        headers.push_str(&format!(
+            "Received: from {} ([{}]:{})\r\n\tby {} with {}",
+            name,
+            self.peer_addr.ip(),
+            self.peer_addr.port(),
+            HOSTNAME,
+            self.protocol
+        ));
+        if let Some(ref user) = self.auth {
+            headers.push_str(&format!(" (authenticated as {})", user));
+        }
+        if self.recipients.len() == 1 {
+            headers.push_str(&format!("\r\n\tfor <{}>", self.recipients[0]));
+        }
+        headers.push_str(&format!("; {}\r\n", self.received_at.to_rfc2822()));
+
+        headers
+    }
+}
+
+/// Returns the trace headers followed by the message for mailbox formats.
+/// Line endings are converted to LF, except for binary messages which are
+/// stored unchanged.
+pub fn mailbox_content(envelope: &Envelope, message: &[u8]) -> Vec<u8> {
+    let headers = envelope.trace_headers();
+    if envelope.binary {
+        let mut content = headers.into_bytes();
+        content.extend_from_slice(message);
+        return content;
+    }
+
+    let mut content = to_unix_newlines(headers.as_bytes());
+    content.extend_from_slice(&to_unix_newlines(message));
+    content
+}
+
+/// Converts CRLF line endings of the message received over the wire into
+/// the local LF convention used by mailbox formats.
+pub fn to_unix_newlines(data: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(data.len());
+    let mut iter = data.iter().peekable();
+
+    while let Some(&b) = iter.next() {
+        if b == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        result.push(b);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_headers_test1() {
+        let envelope = Envelope::new(
+            "192.0.2.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        let headers = envelope.trace_headers();
+        assert!(
+            headers.starts_with("Return-Path: <sender@example.com>\r\nReceived: from [192.0.2.1]")
+        );
+        assert!(headers.contains("\r\n\tfor <rcpt@example.com>; "));
+        assert!(headers.ends_with("\r\n"));
+    }
+
+    #[test]
+    fn trace_headers_test2() {
+        let envelope = Envelope::new(
+            "192.0.2.1:2525".parse().unwrap(),
+            "",
+            &["a@example.com".to_string(), "b@example.com".to_string()],
+        );
+        let headers = envelope.trace_headers();
+        assert!(headers.starts_with("Return-Path: <>\r\n"));
+        assert!(!headers.contains("for <"));
+        assert!(!headers.contains("authenticated"));
+    }
+
+    #[test]
+    fn trace_headers_test3() {
+        let mut envelope = Envelope::new(
+            "192.0.2.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        envelope.protocol = "ESMTPA";
+        envelope.auth = Some("user".to_string());
+        let headers = envelope.trace_headers();
+        assert!(headers.contains("\tby fakesmtpd with ESMTPA (authenticated as user)\r\n"));
+    }
+
+    #[test]
+    fn open_sink_test1() {
+        assert!(open_sink("/tmp/mail").is_err());
+        assert!(open_sink("maildir:").is_err());
+        assert!(open_sink("mh:/tmp/mail").is_err());
+    }
+
+    #[test]
+    fn to_unix_newlines_test1() {
+        assert_eq!(to_unix_newlines(b"a\r\nb\rc\r\n"), b"a\nb\rc\n");
+    }
+}
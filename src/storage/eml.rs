@@ -0,0 +1,81 @@
+use anyhow::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use super::{Envelope, MessageSink};
+
+/// Stores every message as a separate `<timestamp>-<id>.eml` file.
+#[derive(Debug)]
+pub struct EmlDir {
+    path: PathBuf,
+}
+
+impl EmlDir {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+        fs::create_dir_all(&path)?;
+
+        Ok(EmlDir { path })
+    }
+
+    fn file_name(envelope: &Envelope) -> String {
+        format!(
+            "{}-{:016x}.eml",
+            envelope.received_at.format("%Y%m%dT%H%M%S%.6f"),
+            rand::random::<u64>()
+        )
+    }
+}
+
+impl MessageSink for EmlDir {
+    fn store(&self, envelope: &Envelope, message: &[u8]) -> Result<(), Error> {
+        let name = EmlDir::file_name(envelope);
+        let tmp = self.path.join(format!(".{}.tmp", name));
+
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(envelope.trace_headers().as_bytes())?;
+            file.write_all(message)?;
+            file.sync_all()?;
+        }
+
+        if let Err(err) = fs::rename(&tmp, self.path.join(&name)) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_test1() {
+        let dir = std::env::temp_dir().join(format!("fake-smtpd-eml-{}", std::process::id()));
+        let sink = EmlDir::open(&dir).unwrap();
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+
+        sink.store(&envelope, b"Subject: test\r\n\r\nbody\r\n")
+            .unwrap();
+
+        let files: Vec<PathBuf> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].extension().unwrap(), "eml");
+
+        let stored = fs::read_to_string(&files[0]).unwrap();
+        assert!(stored.ends_with("\r\nSubject: test\r\n\r\nbody\r\n"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
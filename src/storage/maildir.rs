@@ -0,0 +1,114 @@
+use anyhow::Error;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::{mailbox_content, Envelope, MessageSink};
+use crate::proto::HOSTNAME;
+
+#[derive(Debug)]
+pub struct Maildir {
+    path: PathBuf,
+    counter: AtomicUsize,
+}
+
+impl Maildir {
+    /// Opens the Maildir at `path`, creating `tmp`, `new` and `cur`
+    /// subdirectories if they don't exist yet.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref().to_path_buf();
+
+        for dir in &["tmp", "new", "cur"] {
+            fs::create_dir_all(path.join(dir))?;
+        }
+
+        Ok(Maildir {
+            path,
+            counter: AtomicUsize::new(0),
+        })
+    }
+
+    /// Writes the message into `tmp` and then moves it into `new`, so readers
+    /// never observe partially written files.
+    pub fn deliver(&self, envelope: &Envelope, message: &[u8]) -> Result<PathBuf, Error> {
+        let name = self.unique_name();
+        let tmp = self.path.join("tmp").join(&name);
+        let new = self.path.join("new").join(&name);
+
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&mailbox_content(envelope, message))?;
+            file.sync_all()?;
+        }
+
+        if let Err(err) = fs::rename(&tmp, &new) {
+            let _ = fs::remove_file(&tmp);
+            return Err(err.into());
+        }
+
+        Ok(new)
+    }
+
+    fn unique_name(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            now.as_secs(),
+            now.subsec_micros(),
+            process::id(),
+            self.counter.fetch_add(1, Ordering::SeqCst),
+            HOSTNAME
+        )
+    }
+}
+
+impl MessageSink for Maildir {
+    fn store(&self, envelope: &Envelope, message: &[u8]) -> Result<(), Error> {
+        self.deliver(envelope, message).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_test1() {
+        let dir = std::env::temp_dir().join(format!("fake-smtpd-maildir-{}", process::id()));
+        let maildir = Maildir::open(&dir).unwrap();
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+
+        let first = maildir
+            .deliver(&envelope, b"Subject: test\r\n\r\nbody\r\n")
+            .unwrap();
+        let second = maildir
+            .deliver(&envelope, b"Subject: test\r\n\r\nbody\r\n")
+            .unwrap();
+        assert_ne!(first, second);
+        assert_eq!(fs::read_dir(dir.join("tmp")).unwrap().count(), 0);
+
+        let stored = fs::read_to_string(&first).unwrap();
+        assert!(stored.starts_with("Return-Path: <sender@example.com>\nReceived: "));
+        assert!(stored.ends_with("\nSubject: test\n\nbody\n"));
+
+        let mut envelope = envelope;
+        envelope.binary = true;
+        let binary = maildir
+            .deliver(&envelope, b"Subject: test\r\n\r\n\x00\r\n\xff")
+            .unwrap();
+        let stored = fs::read(&binary).unwrap();
+        assert!(stored.ends_with(b"\r\nSubject: test\r\n\r\n\x00\r\n\xff"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,107 @@
+use anyhow::Error;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+use super::{mailbox_content, Envelope, MessageSink};
+
+#[derive(Debug)]
+pub struct Mbox {
+    file: Mutex<File>,
+}
+
+impl Mbox {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Mbox {
+            file: Mutex::new(file),
+        })
+    }
+}
+
+impl MessageSink for Mbox {
+    fn store(&self, envelope: &Envelope, message: &[u8]) -> Result<(), Error> {
+        let entry = format_entry(envelope, message);
+        let mut file = self.file.lock().unwrap();
+
+        file.write_all(&entry)?;
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Formats a single mboxrd entry: the `From ` separator line, the message
+/// with `>`-quoted `From ` lines and a trailing empty line.
+fn format_entry(envelope: &Envelope, message: &[u8]) -> Vec<u8> {
+    let sender = if envelope.from.is_empty() {
+        "MAILER-DAEMON"
+    } else {
+        envelope.from.as_str()
+    };
+
+    let mut entry = format!(
+        "From {} {}\n",
+        sender,
+        envelope.received_at.format("%a %b %e %H:%M:%S %Y")
+    )
+    .into_bytes();
+
+    let content = mailbox_content(envelope, message);
+
+    for line in content.split_inclusive(|&b| b == b'\n') {
+        if needs_quoting(line) {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+    }
+
+    if !entry.ends_with(b"\n") {
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+
+    entry
+}
+
+fn needs_quoting(line: &[u8]) -> bool {
+    let quotes = line.iter().take_while(|&&b| b == b'>').count();
+    line[quotes..].starts_with(b"From ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_entry_test1() {
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "",
+            &["rcpt@example.com".to_string()],
+        );
+        let entry = format_entry(
+            &envelope,
+            b"Subject: test\r\n\r\nFrom here\r\n>From there\r\nbody",
+        );
+        let entry = String::from_utf8(entry).unwrap();
+
+        assert!(entry.starts_with("From MAILER-DAEMON "));
+        assert!(entry.ends_with("\n>From here\n>>From there\nbody\n\n"));
+    }
+
+    #[test]
+    fn format_entry_test2() {
+        let mut envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        envelope.binary = true;
+        let entry = format_entry(&envelope, b"Subject: test\r\n\r\n\x00\r\n\xff");
+
+        assert!(entry.ends_with(b"\r\nSubject: test\r\n\r\n\x00\r\n\xff\n\n"));
+    }
+}
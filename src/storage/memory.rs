@@ -0,0 +1,190 @@
+use anyhow::Error;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use super::{Envelope, MessageSink};
+use crate::proto::mime::Part;
+
+#[derive(Debug, Clone)]
+pub struct StoredMessage {
+    pub id: u64,
+    pub envelope: Envelope,
+    /// Message source including the trace headers added on reception.
+    pub data: Arc<Vec<u8>>,
+    pub mime: Arc<Part>,
+    /// Size of the source and the parsed tree counted toward the limit.
+    size: usize,
+}
+
+#[derive(Debug, Default)]
+struct Messages {
+    queue: VecDeque<StoredMessage>,
+    size: usize,
+}
+
+impl Messages {
+    fn remove(&mut self, idx: usize) -> Option<StoredMessage> {
+        let message = self.queue.remove(idx)?;
+        self.size -= message.size;
+        Some(message)
+    }
+}
+
+/// Keeps the most recent received messages in memory so they can be
+/// inspected later. The oldest messages are evicted once either the number
+/// of messages or their total size exceeds the configured limit.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    messages: Mutex<Messages>,
+    next_id: AtomicU64,
+    max_messages: usize,
+    max_bytes: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_messages: usize, max_bytes: usize) -> Self {
+        MemoryStore {
+            next_id: AtomicU64::new(1),
+            max_messages,
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    pub fn list(&self) -> Vec<StoredMessage> {
+        self.messages
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<StoredMessage> {
+        self.messages
+            .lock()
+            .unwrap()
+            .queue
+            .iter()
+            .find(|message| message.id == id)
+            .cloned()
+    }
+
+    pub fn delete(&self, id: u64) -> bool {
+        let mut messages = self.messages.lock().unwrap();
+        match messages.queue.iter().position(|message| message.id == id) {
+            Some(idx) => messages.remove(idx).is_some(),
+            None => false,
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut messages = self.messages.lock().unwrap();
+        messages.queue.clear();
+        messages.size = 0;
+    }
+}
+
+impl MessageSink for MemoryStore {
+    fn store(&self, envelope: &Envelope, message: &[u8]) -> Result<(), Error> {
+        let mut data = envelope.trace_headers().into_bytes();
+        data.extend_from_slice(message);
+
+        let mime = Part::parse(&data);
+        let message = StoredMessage {
+            id: self.next_id.fetch_add(1, Ordering::SeqCst),
+            envelope: envelope.clone(),
+            size: data.len() + mime.size(),
+            mime: Arc::new(mime),
+            data: Arc::new(data),
+        };
+
+        let mut messages = self.messages.lock().unwrap();
+
+        messages.size += message.size;
+        messages.queue.push_back(message);
+
+        while messages.queue.len() > self.max_messages || messages.size > self.max_bytes {
+            if let Some(evicted) = messages.remove(0) {
+                debug!("evicted message {} from memory store", evicted.id);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_test1() {
+        let store = MemoryStore::new(10, 1024);
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+
+        store.store(&envelope, b"Subject: one\r\n\r\n").unwrap();
+        store.store(&envelope, b"Subject: two\r\n\r\n").unwrap();
+
+        let ids: Vec<u64> = store.list().iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![1, 2]);
+        assert!(store
+            .get(2)
+            .unwrap()
+            .data
+            .ends_with(b"Subject: two\r\n\r\n"));
+
+        assert!(store.delete(1));
+        assert!(!store.delete(1));
+        assert!(store.get(1).is_none());
+
+        store.clear();
+        assert!(store.list().is_empty());
+    }
+
+    #[test]
+    fn store_test2() {
+        let store = MemoryStore::new(2, 1024);
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+
+        for _ in 0..3 {
+            store.store(&envelope, b"Subject: test\r\n\r\n").unwrap();
+        }
+
+        let ids: Vec<u64> = store.list().iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+    }
+
+    #[test]
+    fn store_test3() {
+        let envelope = Envelope::new(
+            "127.0.0.1:2525".parse().unwrap(),
+            "sender@example.com",
+            &["rcpt@example.com".to_string()],
+        );
+        let store = MemoryStore::new(10, usize::MAX);
+        store.store(&envelope, &[b'x'; 100]).unwrap();
+        let size = store.list()[0].size;
+        assert!(size > store.list()[0].data.len());
+
+        let store = MemoryStore::new(10, size * 2);
+        for _ in 0..3 {
+            store.store(&envelope, &[b'x'; 100]).unwrap();
+        }
+        let ids: Vec<u64> = store.list().iter().map(|message| message.id).collect();
+        assert_eq!(ids, vec![2, 3]);
+
+        store.store(&envelope, &vec![b'x'; size * 2]).unwrap();
+        assert!(store.list().is_empty());
+    }
+}
@@ -0,0 +1,121 @@
+use anyhow::{anyhow, Error};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use rustls::{ServerConfig, ServerConnection, StreamOwned};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::Arc;
+
+use crate::proto::HOSTNAME;
+
+/// Connection to the client which is either plain TCP or TCP wrapped into
+/// TLS after STARTTLS.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<StreamOwned<ServerConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// Performs TLS handshake over the plain connection.
+    pub fn upgrade(self, config: Arc<ServerConfig>) -> Result<Stream, Error> {
+        let mut sock = match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(_) => return Err(anyhow!("TLS is already active")),
+        };
+
+        let mut conn = ServerConnection::new(config)?;
+        while conn.is_handshaking() {
+            conn.complete_io(&mut sock)?;
+        }
+
+        Ok(Stream::Tls(Box::new(StreamOwned::new(conn, sock))))
+    }
+
+    /// Underlying TCP connection.
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(stream) => stream,
+            Stream::Tls(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Builds server TLS configuration from PEM encoded certificate chain and
+/// private key. Without them a self-signed certificate is generated.
+pub fn load_config(cert: Option<&str>, key: Option<&str>) -> Result<Arc<ServerConfig>, Error> {
+    let (certs, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (load_certs(cert)?, load_key(key)?),
+        (None, None) => self_signed()?,
+        _ => return Err(anyhow!("both TLS certificate and key must be given")),
+    };
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &str) -> Result<Vec<CertificateDer<'static>>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = rustls_pemfile::certs(&mut reader).collect::<Result<Vec<_>, _>>()?;
+
+    if certs.is_empty() {
+        return Err(anyhow!("{}: no certificates found", path));
+    }
+
+    Ok(certs)
+}
+
+fn load_key(path: &str) -> Result<PrivateKeyDer<'static>, Error> {
+    let mut reader = BufReader::new(File::open(path)?);
+    rustls_pemfile::private_key(&mut reader)?
+        .ok_or_else(|| anyhow!("{}: no private key found", path))
+}
+
+fn self_signed() -> Result<(Vec<CertificateDer<'static>>, PrivateKeyDer<'static>), Error> {
+    let certified =
+        rcgen::generate_simple_self_signed(vec![HOSTNAME.to_string(), "localhost".to_string()])?;
+    let key = PrivatePkcs8KeyDer::from(certified.key_pair.serialize_der());
+
+    Ok((vec![certified.cert.der().clone()], key.into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_config_test1() {
+        assert!(load_config(None, None).is_ok());
+        assert!(load_config(Some("cert.pem"), None).is_err());
+        assert!(load_config(Some("/nonexistent.pem"), Some("/nonexistent.pem")).is_err());
+    }
+}